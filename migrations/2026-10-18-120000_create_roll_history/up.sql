@@ -0,0 +1,14 @@
+CREATE TABLE roll_history
+(
+    id BIGSERIAL PRIMARY KEY,
+    instruction TEXT NOT NULL,
+    rolls INTEGER[] NOT NULL,
+    total INTEGER NOT NULL,
+    room TEXT,
+    user_name TEXT,
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX roll_history_room ON roll_history (room, id);
+CREATE INDEX roll_history_user_name ON roll_history (user_name, id);
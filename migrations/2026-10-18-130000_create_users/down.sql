@@ -0,0 +1,3 @@
+ALTER TABLE roll_history DROP COLUMN user_id;
+
+DROP TABLE users;
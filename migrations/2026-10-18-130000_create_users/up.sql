@@ -0,0 +1,14 @@
+CREATE TABLE users
+(
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('users');
+
+ALTER TABLE roll_history ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE SET NULL;
+
+CREATE INDEX roll_history_user_id ON roll_history (user_id, id);
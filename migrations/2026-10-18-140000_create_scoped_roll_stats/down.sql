@@ -0,0 +1 @@
+DROP TABLE scoped_roll_stats;
@@ -0,0 +1,14 @@
+CREATE TABLE scoped_roll_stats
+(
+    scope TEXT NOT NULL,
+    scope_id TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    bucket_start DATE NOT NULL,
+    die SMALLINT NOT NULL,
+    roll SMALLINT NOT NULL,
+    roll_count BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (scope, scope_id, bucket, bucket_start, die, roll)
+);
+
+SELECT diesel_manage_updated_at('scoped_roll_stats');
@@ -0,0 +1 @@
+DROP TABLE roll_stat_flushes;
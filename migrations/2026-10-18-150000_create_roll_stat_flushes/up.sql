@@ -0,0 +1,5 @@
+CREATE TABLE roll_stat_flushes
+(
+    batch_id TEXT PRIMARY KEY,
+    flushed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+-- Flushes bump updated_at, so it can be used to tell when stats have changed
+SELECT diesel_manage_updated_at('roll_stats');
@@ -0,0 +1 @@
+DROP TABLE advantage_roll_stats;
@@ -0,0 +1,12 @@
+-- The die kept from each roll with advantage or disadvantage, which aren't evenly spread
+CREATE TABLE advantage_roll_stats
+(
+    mode TEXT NOT NULL,
+    die SMALLINT NOT NULL,
+    roll SMALLINT NOT NULL,
+    roll_count BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (mode, die, roll)
+);
+
+SELECT diesel_manage_updated_at('advantage_roll_stats');
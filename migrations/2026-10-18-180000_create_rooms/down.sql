@@ -0,0 +1,2 @@
+DROP TABLE room_gms;
+DROP TABLE rooms;
@@ -0,0 +1,14 @@
+CREATE TABLE rooms
+(
+    name TEXT PRIMARY KEY,
+    owner_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Members who see every roll in a room, on top of its owner
+CREATE TABLE room_gms
+(
+    room TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    PRIMARY KEY (room, user_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE roll_history DROP COLUMN seeded;
@@ -0,0 +1,2 @@
+-- Rolls from a seed the client picked, which say nothing about how fair the dice are
+ALTER TABLE roll_history ADD COLUMN seeded BOOLEAN NOT NULL DEFAULT FALSE;
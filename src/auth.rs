@@ -0,0 +1,159 @@
+//! User accounts, with hashed passwords and JSON Web Tokens to stay logged in
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+// Tokens last a week, about as long as the gap between sessions
+const TOKEN_SECONDS: i64 = 60 * 60 * 24 * 7;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_USERNAME_LENGTH: usize = 32;
+// Checked against when there's no such account, so a missing username takes as long to
+// turn down as a wrong password. Has the same cost as real hashes.
+const DUMMY_HASH: &str = "$2b$12$bctPxRa4v3fpYf574DBMteEgJFWawiD2UbHUTsKmmYwS4A4vuBp06";
+
+/// Who a token was issued to
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Claims {
+    /// User id
+    pub sub: i32,
+    pub username: String,
+    /// Expiry, in seconds since the epoch
+    pub exp: i64,
+}
+
+/// Hash a password to store
+///
+/// # Errors
+///
+/// Will return `BcryptError` if the password can't be hashed
+pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+}
+
+/// Check a password against a stored hash
+#[must_use]
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// Check a password against an account's hash, if there is an account, taking just as long
+/// either way
+#[must_use]
+pub fn verify_login(password: &str, hash: Option<&str>) -> bool {
+    let valid = verify_password(password, hash.unwrap_or(DUMMY_HASH));
+    valid && hash.is_some()
+}
+
+/// Why new account details were turned down, if they were
+#[must_use]
+pub fn check_signup(username: &str, password: &str) -> Option<String> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH {
+        Some(format!(
+            "Usernames have to be from 1 to {} characters.",
+            MAX_USERNAME_LENGTH
+        ))
+    } else if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Some(String::from(
+            "Usernames can only have letters, numbers, _ and -.",
+        ))
+    } else if password.chars().count() < MIN_PASSWORD_LENGTH {
+        Some(format!(
+            "Passwords have to be at least {} characters.",
+            MIN_PASSWORD_LENGTH
+        ))
+    } else {
+        None
+    }
+}
+
+/// Issue a token for a user
+///
+/// # Errors
+///
+/// Will return an error if the claims can't be encoded
+pub fn issue_token(
+    user_id: i32,
+    username: &str,
+    secret: &[u8],
+) -> jsonwebtoken::errors::Result<String> {
+    let claims = Claims {
+        sub: user_id,
+        username: username.to_string(),
+        exp: Utc::now().timestamp() + TOKEN_SECONDS,
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+}
+
+/// Check a token, returning who it was issued to
+///
+/// # Errors
+///
+/// Will return an error if the token is invalid or has expired
+pub fn verify_token(token: &str, secret: &[u8]) -> jsonwebtoken::errors::Result<Claims> {
+    decode(
+        token,
+        &DecodingKey::from_secret(secret),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_verify_login() {
+        assert!(DUMMY_HASH.starts_with(&format!("$2b${}$", bcrypt::DEFAULT_COST)));
+        let hash = hash_password("correct horse").unwrap();
+        assert!(verify_login("correct horse", Some(&hash)));
+        assert!(!verify_login("battery staple", Some(&hash)));
+        assert!(!verify_login("d20 dummy password", None));
+    }
+
+    #[test]
+    fn test_check_signup() {
+        assert_eq!(check_signup("mira_the-bard", "longenough"), None);
+        assert!(check_signup("", "longenough").is_some());
+        assert!(check_signup("mira the bard", "longenough").is_some());
+        assert!(check_signup("mira", "short").is_some());
+    }
+
+    #[test]
+    fn test_token() {
+        let token = issue_token(7, "mira", b"secret").unwrap();
+        let claims = verify_token(&token, b"secret").unwrap();
+        assert_eq!(claims.sub, 7);
+        assert_eq!(claims.username, "mira");
+        assert!(verify_token(&token, b"other").is_err());
+        assert!(verify_token("not a token", b"secret").is_err());
+
+        let expired = Claims {
+            exp: Utc::now().timestamp() - TOKEN_SECONDS,
+            ..claims
+        };
+        let token = encode(
+            &Header::default(),
+            &expired,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(verify_token(&token, b"secret").is_err());
+    }
+}
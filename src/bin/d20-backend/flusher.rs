@@ -0,0 +1,61 @@
+use d20::stats::flush;
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, Pool},
+};
+use r2d2_redis::RedisConnectionManager;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tide::log::{error, info};
+
+/// Flushes roll stats from Redis into Postgres in the background, every so often
+pub struct Flusher {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+fn flush(db: &Pool<ConnectionManager<PgConnection>>, redis: &Pool<RedisConnectionManager>) {
+    let result = db
+        .get()
+        .map_err(flush::FlushError::from)
+        .and_then(|d_conn| Ok((d_conn, redis.get()?)))
+        .and_then(|(d_conn, mut r_conn)| flush::flush_with_lock(&d_conn, &mut r_conn));
+    match result {
+        Ok(Some(report)) => info!(
+            "Flushed {} roll stats from {} batches in {}ms ({} already saved)",
+            report.stats, report.batches, report.duration_ms, report.skipped
+        ),
+        Ok(None) => info!("Skipped flushing roll stats, another instance is flushing"),
+        Err(e) => error!("Error flushing roll stats: {}", e),
+    }
+}
+
+impl Flusher {
+    /// Start flushing on a thread of its own, since diesel and redis block
+    pub fn start(
+        db: Pool<ConnectionManager<PgConnection>>,
+        redis: Pool<RedisConnectionManager>,
+        interval: Duration,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                flush(&db, &redis);
+            }
+            // One last time, so nothing is left sitting in Redis
+            flush(&db, &redis);
+        });
+        Self { stop, handle }
+    }
+
+    /// Stop flushing, after one final flush
+    pub fn stop(self) {
+        self.stop.send(()).ok();
+        if self.handle.join().is_err() {
+            error!("Roll stats flusher panicked");
+        }
+    }
+}
@@ -1,49 +1,1221 @@
-use crate::State;
+use crate::{rooms::RoomRoll, State};
+use async_std::task;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use d20::{
-    dice_roller::{self, RollInstruction},
-    REDIS_KEY_ROLL_STATS,
+    auth::{self, Claims},
+    character::{self, Method},
+    checks::{self, Attack, Save},
+    dice_roller::{
+        self, Advantage, CritRange, DiceRolls, Expression, RollError, RollErrorKind,
+        RollInstruction, RollResult,
+    },
+    fairness,
+    initiative::{Encounter, NewCombatant, NewDuration},
+    models::{
+        NewRollHistory, NewRoom, NewRoomGm, NewUser, RollHistory, Room, ScopedRollStat, User,
+    },
+    probability, provably_fair,
+    r2d2_rng::{RollSeed, SeededRng},
+    schema::{roll_history, room_gms, rooms, scoped_roll_stats, users},
+    stats::{self, Bucket, DieStats, Scope, StatKey, REDIS_KEY_SCOPED_STATS},
+    REDIS_KEY_ADVANTAGE_STATS, REDIS_KEY_DISADVANTAGE_STATS, REDIS_KEY_ENCOUNTER,
+    REDIS_KEY_FAIR_SESSION, REDIS_KEY_ROLL_STATS,
 };
-use r2d2_redis::redis::pipe;
-use serde::Deserialize;
-use std::collections::HashMap;
-use tide::{prelude::json, Request};
+use diesel::{pg::PgConnection, prelude::*};
+use r2d2_redis::redis::{self, pipe, Commands, Script};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom, future::Future, iter, pin::Pin};
+use tide::{
+    http::headers::{AUTHORIZATION, CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+    prelude::json,
+    sse::Sender,
+    Next, Request, Response, StatusCode,
+};
+
+// Fair sessions that are never revealed are cleaned up after a day
+const FAIR_SESSION_SECONDS: usize = 60 * 60 * 24;
+// Encounters nobody has touched in a week are cleaned up
+const ENCOUNTER_SECONDS: usize = 60 * 60 * 24 * 7;
+// Rolls on a page of history
+const DEFAULT_HISTORY_PAGE: i64 = 50;
+const MAX_HISTORY_PAGE: i64 = 200;
+// Most rolls a single batch can make, counting repeats
+const MAX_BATCH_ROLLS: usize = 100;
 
 #[derive(Deserialize)]
 pub struct RollQuery {
     roll: String,
+    seed: Option<u64>,
+    crit_range: Option<i32>,
+    crit_damage: Option<bool>,
+    /// Room to share the roll with, which needs a login
+    room: Option<String>,
+    /// Only share with the room's GMs
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RollBody {
+    #[serde(flatten)]
+    instruction: RollInstruction,
+    seed: Option<u64>,
+    crit_range: Option<i32>,
+    crit_damage: Option<bool>,
+    /// Room to share the roll with, which needs a login
+    room: Option<String>,
+    /// Only share with the room's GMs
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    roll: String,
+    seed: u64,
+    position: u64,
+}
+
+#[derive(Deserialize)]
+pub struct OddsQuery {
+    roll: String,
+    at_least: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ScopedStatsQuery {
+    bucket: Option<Bucket>,
+    /// Only buckets starting on or after this date
+    since: Option<NaiveDate>,
+    die: Option<i16>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    room: Option<String>,
+    user: Option<String>,
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    /// Only rolls older than this id, to fetch the next page
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RoomRollBody {
+    roll: String,
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RoomGmBody {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct FairRollBody {
+    roll: String,
+    client_seed: String,
+}
+
+/// Either a roll command or a structured instruction
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum BatchInput {
+    Command { roll: String },
+    Instruction(RollInstruction),
+}
+
+#[derive(Deserialize)]
+pub struct BatchItem {
+    #[serde(flatten)]
+    input: BatchInput,
+    label: Option<String>,
+    repeat: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    label: Option<String>,
+    #[serde(flatten)]
+    result: RollResult,
+}
+
+/// Totals across every roll in a batch
+#[derive(Serialize)]
+pub struct BatchAggregate {
+    count: usize,
+    total: i64,
+    min: Option<i32>,
+    max: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct AbilitiesBody {
+    #[serde(flatten)]
+    method: Method,
+    seed: Option<u64>,
+}
+
+fn unauthorized(message: &'static str) -> tide::Error {
+    tide::Error::from_str(StatusCode::Unauthorized, message)
+}
+
+fn token_response(state: &State, user: &User, status: StatusCode) -> tide::Result {
+    let token = auth::issue_token(user.id, &user.username, &state.jwt_secret)?;
+    let mut res = Response::new(status);
+    res.set_body(json!({ "user": user, "token": token }));
+    Ok(res)
+}
+
+/// Sign up for an account, which logs in straight away
+pub async fn signup(mut req: Request<State>) -> tide::Result {
+    let body: Credentials = req.body_json().await?;
+    if let Some(message) = auth::check_signup(&body.username, &body.password) {
+        return Err(tide::Error::from_str(
+            StatusCode::UnprocessableEntity,
+            message,
+        ));
+    }
+    let state = req.state();
+    // Hashing takes a while on purpose, so keep it off the executor
+    let password = body.password.clone();
+    let password_hash = task::spawn_blocking(move || auth::hash_password(&password)).await?;
+    let user = diesel::insert_into(users::table)
+        .values(&NewUser {
+            username: &body.username,
+            password_hash: &password_hash,
+        })
+        .get_result::<User>(&state.db.get()?)
+        .map_err(|e| match e {
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => tide::Error::from_str(StatusCode::Conflict, "That username is taken."),
+            e => e.into(),
+        })?;
+    token_response(state, &user, StatusCode::Created)
+}
+
+pub async fn login(mut req: Request<State>) -> tide::Result {
+    let body: Credentials = req.body_json().await?;
+    let state = req.state();
+    let user = users::table
+        .filter(users::username.eq(&body.username))
+        .first::<User>(&state.db.get()?)
+        .optional()?;
+    let hash = user.as_ref().map(|user| user.password_hash.clone());
+    let valid =
+        task::spawn_blocking(move || auth::verify_login(&body.password, hash.as_deref())).await;
+    let user = user
+        .filter(|_| valid)
+        .ok_or_else(|| unauthorized("Wrong username or password."))?;
+    token_response(state, &user, StatusCode::Ok)
+}
+
+/// The logged in user
+pub async fn current_user(req: Request<State>) -> tide::Result {
+    let claims = req
+        .ext::<Claims>()
+        .ok_or_else(|| unauthorized("Log in to see your account."))?;
+    let user = users::table
+        .find(claims.sub)
+        .first::<User>(&req.state().db.get()?)
+        .optional()?
+        .ok_or_else(|| unauthorized("That account no longer exists."))?;
+    Ok(json!(&user).into())
+}
+
+/// Check the bearer token on a request, if there is one, and attach who it belongs to.
+/// Requests without a token carry on anonymously.
+pub fn authenticate<'a>(
+    mut req: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        if let Some(header) = req.header(AUTHORIZATION) {
+            let token = header
+                .as_str()
+                .strip_prefix("Bearer ")
+                .ok_or_else(|| unauthorized("Use a bearer token to log in."))?;
+            let claims = auth::verify_token(token, &req.state().jwt_secret)
+                .map_err(|_| unauthorized("Invalid or expired token. Log in again."))?;
+            req.set_ext(claims);
+        }
+        Ok(next.run(req).await)
+    })
+}
+
+/// Who and where a roll counts towards in the stats, on top of the global counts
+fn stat_scopes(user: Option<&Claims>, room: Option<&str>) -> Vec<Scope> {
+    user.map(|u| Scope::User(u.sub))
+        .into_iter()
+        .chain(room.map(|room| Scope::Room(room.to_string())))
+        .collect()
 }
 
 /// Log stats to redis
-pub fn roll_stats(state: &State, die: i32, rolls: &[i32]) {
+pub fn roll_stats<'a>(
+    state: &State,
+    scopes: &[Scope],
+    terms: impl IntoIterator<Item = &'a DiceRolls>,
+) {
     let pool = state.redis.clone();
     let mut conn = pool.get().unwrap();
     let mut stat_map = HashMap::new();
-    for roll in rolls {
-        *stat_map.entry(roll).or_insert(0) += 1;
+    let scoped_keys: Vec<String> = scopes
+        .iter()
+        .flat_map(|scope| StatKey::all(scope, Utc::now().naive_utc().date()))
+        .map(|key| key.redis_key())
+        .collect();
+    // Only numbered dice have a place in the stats
+    for term in terms {
+        if let Some(die) = term.die {
+            for roll in term.faces() {
+                for key in
+                    iter::once(REDIS_KEY_ROLL_STATS).chain(scoped_keys.iter().map(String::as_str))
+                {
+                    *stat_map.entry((key, die, roll)).or_insert(0) += 1;
+                }
+            }
+            // Every face still counts above, but the dice picked are tracked by mode too
+            let mode_key = match term.advantage {
+                Some(Advantage::Advantage(_)) => Some(REDIS_KEY_ADVANTAGE_STATS),
+                Some(Advantage::Disadvantage) => Some(REDIS_KEY_DISADVANTAGE_STATS),
+                Some(Advantage::Cancelled) | None => None,
+            };
+            if let Some(key) = mode_key {
+                for roll in term.rolls.iter().filter(|r| r.kept) {
+                    *stat_map.entry((key, die, roll.value)).or_insert(0) += 1;
+                }
+            }
+        }
     }
     let mut pipeline = pipe();
-    for (roll, count) in stat_map {
-        pipeline.hincr(REDIS_KEY_ROLL_STATS, format!("{}:{}", die, roll), count);
+    for ((key, die, roll), count) in stat_map {
+        pipeline.hincr(key, format!("{}:{}", die, roll), count);
+    }
+    // Scoped hashes are listed so the flush can find them
+    if !scoped_keys.is_empty() {
+        pipeline.sadd(REDIS_KEY_SCOPED_STATS, &scoped_keys[..]);
     }
     pipeline.execute(&mut *conn);
 }
 
-fn roll_to_response(state: &State, instruction: RollInstruction) -> tide::Result {
-    let die = instruction.die;
-    let pool = state.rng.clone();
-    let mut rng = pool.get()?;
-    let result = dice_roller::roll(&mut *rng, instruction)?;
-    roll_stats(state, die, &result.rolls);
-    Ok(json!(&result).into())
+fn roll_with_options(
+    state: &State,
+    scopes: &[Scope],
+    expr: &Expression,
+    seed: Option<u64>,
+    crit_range: Option<i32>,
+    crit_damage: bool,
+) -> tide::Result<RollResult> {
+    let crit_range = crit_range.map(CritRange::new).transpose()?;
+    let crit_expr;
+    let expr = if crit_damage {
+        crit_expr = expr.crit_damage();
+        &crit_expr
+    } else {
+        expr
+    };
+    // Rolls from a seed the client picked aren't random, so leave them out of the stats
+    let mut result = if let Some(seed) = seed {
+        dice_roller::roll_seeded(&mut SeededRng::new(seed), expr, state.limits)?
+    } else {
+        // A stream of its own, so the seed it returns can't be used to predict other rolls
+        let result = dice_roller::roll_seeded(&mut SeededRng::from_entropy(), expr, state.limits)?;
+        roll_stats(state, scopes, &result.terms);
+        result
+    };
+    if let Some(crit_range) = crit_range {
+        result.set_crit_range(crit_range);
+    }
+    Ok(result)
+}
+
+fn room_not_found() -> tide::Error {
+    tide::Error::from_str(StatusCode::NotFound, "No room with that name.")
+}
+
+/// Whether a user is one of a room's GMs, or `None` if there is no such room.
+/// The owner always is.
+fn room_role(conn: &PgConnection, room: &str, user_id: i32) -> QueryResult<Option<bool>> {
+    let owner: Option<i32> = rooms::table
+        .find(room)
+        .select(rooms::owner_id)
+        .first(conn)
+        .optional()?;
+    match owner {
+        None => Ok(None),
+        Some(owner) if owner == user_id => Ok(Some(true)),
+        Some(_) => diesel::select(diesel::dsl::exists(
+            room_gms::table
+                .filter(room_gms::room.eq(room))
+                .filter(room_gms::user_id.eq(user_id)),
+        ))
+        .get_result(conn)
+        .map(Some),
+    }
+}
+
+/// Make sure a roll can be shared with a room before rolling it
+fn check_room_roll(
+    state: &State,
+    user: Option<&Claims>,
+    room: Option<&str>,
+    seed: Option<u64>,
+) -> tide::Result<()> {
+    if let Some(room) = room {
+        // A client can pick a seed that gives any roll they like
+        if seed.is_some() {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "Rolls from a seed you picked can't be shared with a room.",
+            ));
+        }
+        let user = user.ok_or_else(|| unauthorized("Log in to roll in a room."))?;
+        room_role(&*state.db.get()?, room, user.sub)?.ok_or_else(room_not_found)?;
+    }
+    Ok(())
+}
+
+/// Save a roll to the history, and show it to everyone in its room if it was made in one.
+/// Rolls in a room should be checked with `check_room_roll` first.
+/// History rows for rolls made together
+fn history_rows<'a>(
+    user: Option<&'a Claims>,
+    room: Option<&'a str>,
+    private: bool,
+    seeded: bool,
+    results: impl IntoIterator<Item = &'a RollResult>,
+) -> Vec<NewRollHistory<'a>> {
+    results
+        .into_iter()
+        .map(|result| NewRollHistory {
+            instruction: &result.instruction,
+            rolls: &result.rolls,
+            total: result.total,
+            room,
+            user_name: user.map(|u| u.username.as_str()),
+            private,
+            user_id: user.map(|u| u.sub),
+            seeded,
+        })
+        .collect()
+}
+
+/// Save rolls made outside of a room to the history
+fn record_rolls<'a>(
+    state: &State,
+    user: Option<&'a Claims>,
+    seeded: bool,
+    results: impl IntoIterator<Item = &'a RollResult>,
+) -> tide::Result<()> {
+    let rows = history_rows(user, None, false, seeded, results);
+    if !rows.is_empty() {
+        diesel::insert_into(roll_history::table)
+            .values(&rows)
+            .execute(&state.db.get()?)?;
+    }
+    Ok(())
+}
+
+fn record_roll(
+    state: &State,
+    user: Option<&Claims>,
+    room: Option<&str>,
+    private: bool,
+    seeded: bool,
+    result: &RollResult,
+) -> tide::Result<()> {
+    diesel::insert_into(roll_history::table)
+        .values(&history_rows(
+            user,
+            room,
+            private,
+            seeded,
+            iter::once(result),
+        ))
+        .execute(&state.db.get()?)?;
+    if let (Some(room), Some(user)) = (room, user) {
+        state.rooms.broadcast(
+            room,
+            &RoomRoll {
+                user_id: user.sub,
+                name: user.username.clone(),
+                private,
+                result: json!(result),
+            },
+        );
+    }
+    Ok(())
 }
 
 pub async fn parse_roll(req: Request<State>) -> tide::Result {
     let query: RollQuery = req.query()?;
-    let instruction = dice_roller::parse_roll(&query.roll)?;
-    roll_to_response(req.state(), instruction)
+    let expr = dice_roller::parse_roll(&query.roll)?;
+    let state = req.state();
+    check_room_roll(state, req.ext(), query.room.as_deref(), query.seed)?;
+    let result = roll_with_options(
+        state,
+        &stat_scopes(req.ext(), query.room.as_deref()),
+        &expr,
+        query.seed,
+        query.crit_range,
+        query.crit_damage.unwrap_or_default(),
+    )?;
+    record_roll(
+        state,
+        req.ext(),
+        query.room.as_deref(),
+        query.private,
+        query.seed.is_some(),
+        &result,
+    )?;
+    Ok(json!(&result).into())
 }
 
 pub async fn roll(mut req: Request<State>) -> tide::Result {
-    let body = req.body_json().await?;
-    roll_to_response(req.state(), body)
+    let body: RollBody = req.body_json().await?;
+    let state = req.state();
+    let user = req.ext::<Claims>();
+    check_room_roll(state, user, body.room.as_deref(), body.seed)?;
+    let result = roll_with_options(
+        state,
+        &stat_scopes(user, body.room.as_deref()),
+        &body.instruction.into(),
+        body.seed,
+        body.crit_range,
+        body.crit_damage.unwrap_or_default(),
+    )?;
+    record_roll(
+        state,
+        user,
+        body.room.as_deref(),
+        body.private,
+        body.seed.is_some(),
+        &result,
+    )?;
+    Ok(json!(&result).into())
+}
+
+/// Rolls made in the past, newest first. Private rolls are left out, other than your own.
+pub async fn roll_history(req: Request<State>) -> tide::Result {
+    let query: HistoryQuery = req.query()?;
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE);
+    if !(1..=MAX_HISTORY_PAGE).contains(&limit) {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("Pages can have from 1 to {} rolls.", MAX_HISTORY_PAGE),
+        ));
+    }
+    let mut rolls = roll_history::table.into_boxed();
+    rolls = match req.ext::<Claims>() {
+        Some(user) => rolls.filter(
+            roll_history::private
+                .eq(false)
+                .or(roll_history::user_id.eq(user.sub)),
+        ),
+        None => rolls.filter(roll_history::private.eq(false)),
+    };
+    if let Some(room) = query.room {
+        rolls = rolls.filter(roll_history::room.eq(room));
+    }
+    if let Some(user) = query.user {
+        rolls = rolls.filter(roll_history::user_name.eq(user));
+    }
+    if let Some(since) = query.since {
+        rolls = rolls.filter(roll_history::created_at.ge(since));
+    }
+    if let Some(until) = query.until {
+        rolls = rolls.filter(roll_history::created_at.lt(until));
+    }
+    if let Some(before) = query.before {
+        rolls = rolls.filter(roll_history::id.lt(before));
+    }
+    // One extra to tell whether there is another page
+    let mut rolls: Vec<RollHistory> = rolls
+        .order(roll_history::id.desc())
+        .limit(limit + 1)
+        .load(&req.state().db.get()?)?;
+    let limit = usize::try_from(limit)?;
+    let more = rolls.len() > limit;
+    rolls.truncate(limit);
+    let next = if more {
+        rolls.last().map(|r| r.id)
+    } else {
+        None
+    };
+    Ok(json!({ "rolls": rolls, "next": next }).into())
+}
+
+/// Saved stats for a user or room, a row per face per day or week
+fn scoped_stats(req: &Request<State>, scope: &Scope) -> tide::Result {
+    let query: ScopedStatsQuery = req.query()?;
+    let mut stats = scoped_roll_stats::table
+        .filter(scoped_roll_stats::scope.eq(scope.kind()))
+        .filter(scoped_roll_stats::scope_id.eq(scope.id()))
+        .filter(scoped_roll_stats::bucket.eq(query.bucket.unwrap_or(Bucket::Week).to_string()))
+        .into_boxed();
+    if let Some(since) = query.since {
+        stats = stats.filter(scoped_roll_stats::bucket_start.ge(since));
+    }
+    if let Some(die) = query.die {
+        stats = stats.filter(scoped_roll_stats::die.eq(die));
+    }
+    let stats: Vec<ScopedRollStat> = stats
+        .order((
+            scoped_roll_stats::bucket_start,
+            scoped_roll_stats::die,
+            scoped_roll_stats::roll,
+        ))
+        .load(&req.state().db.get()?)?;
+    Ok(json!(&stats).into())
+}
+
+/// Stats for the logged in user's own rolls
+pub async fn user_stats(req: Request<State>) -> tide::Result {
+    let user = req
+        .ext::<Claims>()
+        .ok_or_else(|| unauthorized("Log in to see your stats."))?;
+    scoped_stats(&req, &Scope::User(user.sub))
+}
+
+pub async fn room_stats(req: Request<State>) -> tide::Result {
+    let room = Scope::Room(req.param("room")?.to_string());
+    scoped_stats(&req, &room)
+}
+
+/// Changes whenever a flush saves new counts or a roll adds to the pending ones
+fn stats_etag<'a>(dice: impl Iterator<Item = &'a DieStats> + Clone) -> String {
+    let updated_at = dice.clone().filter_map(|d| d.updated_at).max();
+    let pending: i64 = dice.map(|d| d.pending).sum();
+    format!(
+        "\"{}-{}\"",
+        updated_at.map_or(0, |t| t.timestamp_nanos()),
+        pending
+    )
+}
+
+/// Respond with stats, or Not Modified if the client already has them
+fn stats_response(req: &Request<State>, body: tide::Body, etag: &str) -> Response {
+    let cached = req.header(IF_NONE_MATCH).is_some_and(|values| {
+        values
+            .iter()
+            .flat_map(|value| value.as_str().split(','))
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    });
+    let mut res = if cached {
+        Response::new(StatusCode::NotModified)
+    } else {
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_body(body);
+        res
+    };
+    res.insert_header(ETAG, etag);
+    // Pending counts change all the time, so always check back
+    res.insert_header(CACHE_CONTROL, "no-cache");
+    res
+}
+
+/// Counts for every die, saved or still waiting to be flushed
+pub async fn all_roll_stats(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let d_conn = state.db.get()?;
+    let mut r_conn = state.redis.get()?;
+    let dice = stats::current_roll_stats(&d_conn, &mut r_conn, None)
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+    let etag = stats_etag(dice.iter());
+    Ok(stats_response(&req, json!(&dice).into(), &etag))
+}
+
+/// Counts for the die kept from rolls with advantage and disadvantage
+pub async fn advantage_roll_stats(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let d_conn = state.db.get()?;
+    let mut r_conn = state.redis.get()?;
+    let modes = stats::current_advantage_stats(&d_conn, &mut r_conn)
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+    let etag = stats_etag(modes.values().flatten());
+    Ok(stats_response(&req, json!(&modes).into(), &etag))
+}
+
+/// Counts for each face of one die, saved or still waiting to be flushed
+pub async fn die_roll_stats(req: Request<State>) -> tide::Result {
+    let not_found = || tide::Error::from_str(StatusCode::NotFound, "No rolls of that die yet.");
+    let param = req.param("die")?;
+    // Allow d20 as well as 20
+    let die: i16 = param
+        .strip_prefix('d')
+        .unwrap_or(param)
+        .parse()
+        .map_err(|_| not_found())?;
+    let state = req.state();
+    let d_conn = state.db.get()?;
+    let mut r_conn = state.redis.get()?;
+    let found = stats::current_roll_stats(&d_conn, &mut r_conn, Some(die))
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+    let etag = stats_etag(found.iter());
+    let counts = found.first().ok_or_else(not_found)?;
+    Ok(stats_response(&req, json!(counts).into(), &etag))
+}
+
+/// How fair each die looks, from the saved stats and recent history
+pub async fn fairness(req: Request<State>) -> tide::Result {
+    let conn = req.state().db.get()?;
+    let reports = fairness::fairness_reports(&conn, None)?;
+    Ok(json!(&reports).into())
+}
+
+/// Open a room, run by the logged in user
+pub async fn open_room(req: Request<State>) -> tide::Result {
+    let user = req
+        .ext::<Claims>()
+        .ok_or_else(|| unauthorized("Log in to open a room."))?;
+    let room = diesel::insert_into(rooms::table)
+        .values(&NewRoom {
+            name: req.param("room")?,
+            owner_id: user.sub,
+        })
+        .get_result::<Room>(&req.state().db.get()?)
+        .map_err(|e| match e {
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => tide::Error::from_str(StatusCode::Conflict, "That room is taken."),
+            e => e.into(),
+        })?;
+    let mut res = Response::new(StatusCode::Created);
+    res.set_body(json!(&room));
+    Ok(res)
+}
+
+/// The room a request is for, as long as the logged in user owns it
+fn owned_room(req: &Request<State>, conn: &PgConnection) -> tide::Result<String> {
+    let user = req
+        .ext::<Claims>()
+        .ok_or_else(|| unauthorized("Log in to manage a room."))?;
+    let room = req.param("room")?;
+    let owner: i32 = rooms::table
+        .find(room)
+        .select(rooms::owner_id)
+        .first(conn)
+        .optional()?
+        .ok_or_else(room_not_found)?;
+    if owner != user.sub {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "Only the room's owner can do that.",
+        ));
+    }
+    Ok(room.to_string())
+}
+
+fn user_id(conn: &PgConnection, username: &str) -> tide::Result<i32> {
+    users::table
+        .filter(users::username.eq(username))
+        .select(users::id)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "No user with that name."))
+}
+
+/// Let someone see every roll in a room
+pub async fn add_room_gm(mut req: Request<State>) -> tide::Result {
+    let body: RoomGmBody = req.body_json().await?;
+    let conn = req.state().db.get()?;
+    let room = owned_room(&req, &conn)?;
+    diesel::insert_into(room_gms::table)
+        .values(&NewRoomGm {
+            room: &room,
+            user_id: user_id(&conn, &body.username)?,
+        })
+        .on_conflict_do_nothing()
+        .execute(&conn)?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// Stop someone being a GM in a room. Members who already joined keep seeing private rolls
+/// until they join again.
+pub async fn remove_room_gm(req: Request<State>) -> tide::Result {
+    let conn = req.state().db.get()?;
+    let room = owned_room(&req, &conn)?;
+    let gm = user_id(&conn, req.param("username")?)?;
+    diesel::delete(
+        room_gms::table
+            .filter(room_gms::room.eq(&room))
+            .filter(room_gms::user_id.eq(gm)),
+    )
+    .execute(&conn)?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// Listen to every roll made in a room, as server-sent events
+pub async fn join_room(req: Request<State>, sender: Sender) -> tide::Result<()> {
+    let user = req
+        .ext::<Claims>()
+        .ok_or_else(|| unauthorized("Log in to join a room."))?;
+    let room = req.param("room")?;
+    let state = req.state();
+    let gm = room_role(&*state.db.get()?, room, user.sub)?.ok_or_else(room_not_found)?;
+    let rolls = state.rooms.join(room, user.sub, gm);
+    while let Ok(roll) = rolls.recv().await {
+        // Fails once the member has gone, which also drops them from the room
+        sender
+            .send("roll", serde_json::to_string(&roll)?, None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Roll in a room without going through `/roll/`
+pub async fn room_roll(mut req: Request<State>) -> tide::Result {
+    let body: RoomRollBody = req.body_json().await?;
+    let state = req.state();
+    let expr = dice_roller::parse_roll(&body.roll)?;
+    let room = req.param("room")?;
+    check_room_roll(state, req.ext(), Some(room), None)?;
+    let result = roll_with_options(
+        state,
+        &stat_scopes(req.ext(), Some(room)),
+        &expr,
+        None,
+        None,
+        false,
+    )?;
+    record_roll(state, req.ext(), Some(room), body.private, false, &result)?;
+    Ok(json!(&result).into())
+}
+
+pub async fn roll_batch(mut req: Request<State>) -> tide::Result {
+    let body: Vec<BatchItem> = req.body_json().await?;
+    let state = req.state();
+
+    // Check everything before rolling anything
+    let mut batch = Vec::new();
+    let mut total: usize = 0;
+    for item in body {
+        let expr = match item.input {
+            BatchInput::Command { roll } => dice_roller::parse_roll(&roll)?,
+            BatchInput::Instruction(instruction) => instruction.into(),
+        };
+        let repeat = item.repeat.unwrap_or(1);
+        if repeat < 1 {
+            return Err(
+                RollError::new(RollErrorKind::DiceCount, "You have to roll something!").into(),
+            );
+        }
+        // Repeats come from the client, so the total could overflow
+        total = total.checked_add(repeat).ok_or_else(|| {
+            tide::Error::from_str(StatusCode::BadRequest, "Too many rolls in the batch.")
+        })?;
+        if repeat > MAX_BATCH_ROLLS || total > MAX_BATCH_ROLLS {
+            return Err(RollError::new(
+                RollErrorKind::DiceCount,
+                format!("A batch can have up to {} rolls.", MAX_BATCH_ROLLS),
+            )
+            .into());
+        }
+        batch.push((item.label, expr, repeat));
+    }
+
+    // One stream for the whole batch. Each result's seed and position replays it, and
+    // being fresh from the OS it says nothing about other requests.
+    let mut rng = SeededRng::from_entropy();
+    let mut results = Vec::new();
+    for (label, expr, repeat) in batch {
+        for _ in 0..repeat {
+            results.push(BatchResult {
+                label: label.clone(),
+                result: dice_roller::roll_seeded(&mut rng, &expr, state.limits)?,
+            });
+        }
+    }
+    roll_stats(
+        state,
+        &stat_scopes(req.ext(), None),
+        results.iter().flat_map(|r| &r.result.terms),
+    );
+    record_rolls(state, req.ext(), false, results.iter().map(|r| &r.result))?;
+
+    let totals = results.iter().map(|r| r.result.total);
+    let aggregate = BatchAggregate {
+        count: results.len(),
+        total: totals.clone().map(i64::from).sum(),
+        min: totals.clone().min(),
+        max: totals.max(),
+    };
+    Ok(json!({ "results": results, "aggregate": aggregate }).into())
+}
+
+pub async fn attack(mut req: Request<State>) -> tide::Result {
+    let body: Attack = req.body_json().await?;
+    let state = req.state();
+    let result = checks::attack(&mut SeededRng::from_entropy(), &body, state.limits)?;
+    roll_stats(
+        state,
+        &stat_scopes(req.ext(), None),
+        result.results().flat_map(|r| &r.terms),
+    );
+    record_rolls(state, req.ext(), false, result.results())?;
+    Ok(json!(&result).into())
+}
+
+pub async fn save(mut req: Request<State>) -> tide::Result {
+    let body: Save = req.body_json().await?;
+    let state = req.state();
+    let result = checks::save(&mut SeededRng::from_entropy(), &body, state.limits)?;
+    roll_stats(
+        state,
+        &stat_scopes(req.ext(), None),
+        result.results().flat_map(|r| &r.terms),
+    );
+    record_rolls(state, req.ext(), false, result.results())?;
+    Ok(json!(&result).into())
+}
+
+pub async fn abilities(mut req: Request<State>) -> tide::Result {
+    let body: AbilitiesBody = req.body_json().await?;
+    let state = req.state();
+    // As with other rolls, scores from a seed the client picked stay out of the stats
+    let abilities = if let Some(seed) = body.seed {
+        character::generate(&mut SeededRng::new(seed), body.method, state.limits)?
+    } else {
+        let abilities =
+            character::generate(&mut SeededRng::from_entropy(), body.method, state.limits)?;
+        roll_stats(
+            state,
+            &stat_scopes(req.ext(), None),
+            abilities.rolls.iter().flat_map(|r| &r.terms),
+        );
+        abilities
+    };
+    record_rolls(state, req.ext(), body.seed.is_some(), &abilities.rolls)?;
+    Ok(json!(&abilities).into())
+}
+
+pub async fn replay_roll(req: Request<State>) -> tide::Result {
+    let query: ReplayQuery = req.query()?;
+    let expr = dice_roller::parse_roll(&query.roll)?;
+    let seed = RollSeed {
+        seed: query.seed,
+        position: query.position,
+    };
+    let result = dice_roller::replay(&expr, req.state().limits, seed)?;
+    Ok(json!(&result).into())
+}
+
+pub async fn roll_odds(req: Request<State>) -> tide::Result {
+    let query: OddsQuery = req.query()?;
+    let expr = dice_roller::parse_roll(&query.roll)?;
+    let summary = probability::summarize(&expr, req.state().limits, query.at_least)?;
+    Ok(json!(&summary).into())
+}
+
+fn fair_session_key(req: &Request<State>) -> tide::Result<String> {
+    Ok(format!(
+        "{}:{}",
+        REDIS_KEY_FAIR_SESSION,
+        req.param("session")?
+    ))
+}
+
+/// Id for a fair session or encounter that nobody can guess
+fn random_id() -> String {
+    let mut id = [0; 16];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+fn fair_session_not_found() -> tide::Error {
+    tide::Error::from_str(
+        StatusCode::NotFound,
+        "No fair session with that id. It may have already been revealed.",
+    )
+}
+
+/// Start a fair session by committing to a server seed
+pub async fn new_fair_session(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    // Straight from the OS, so nothing a player has seen can predict them
+    let session = random_id();
+    let server_seed = provably_fair::server_seed(&mut OsRng);
+    let key = format!("{}:{}", REDIS_KEY_FAIR_SESSION, session);
+    let mut conn = state.redis.get()?;
+    pipe()
+        .hset(&key, "server_seed", &server_seed)
+        .hset(&key, "nonce", 0)
+        .expire(&key, FAIR_SESSION_SECONDS)
+        .query::<()>(&mut *conn)?;
+    Ok(json!({
+        "session": session,
+        "commitment": provably_fair::commitment(&server_seed),
+    })
+    .into())
+}
+
+/// Roll in a fair session, mixing in the client's seed
+pub async fn fair_roll(mut req: Request<State>) -> tide::Result {
+    let body: FairRollBody = req.body_json().await?;
+    let key = fair_session_key(&req)?;
+    let state = req.state();
+    // Checked and counted in one go, so a roll racing a reveal can't bring back a session
+    // that has just been deleted
+    let session: Option<(String, u64)> = Script::new(
+        r#"local seed = redis.call("HGET", KEYS[1], "server_seed")
+        if not seed then return false end
+        return {seed, redis.call("HINCRBY", KEYS[1], "nonce", 1)}"#,
+    )
+    .key(&key)
+    .invoke(&mut *state.redis.get()?)?;
+    let (server_seed, nonce) = session.ok_or_else(fair_session_not_found)?;
+    let (fair, result) = provably_fair::fair_roll(
+        &server_seed,
+        &body.client_seed,
+        nonce,
+        &body.roll,
+        state.limits,
+    )?;
+    roll_stats(state, &stat_scopes(req.ext(), None), &result.terms);
+    record_rolls(state, req.ext(), false, iter::once(&result))?;
+    Ok(json!({ "fair": fair, "result": result }).into())
+}
+
+/// End a fair session, revealing the server seed so its rolls can be checked
+pub async fn reveal_fair_session(req: Request<State>) -> tide::Result {
+    let key = fair_session_key(&req)?;
+    let state = req.state();
+    let session: Option<(String, u64)> = Script::new(
+        r#"local session = redis.call("HMGET", KEYS[1], "server_seed", "nonce")
+        if not session[1] then return false end
+        redis.call("DEL", KEYS[1])
+        return session"#,
+    )
+    .key(&key)
+    .invoke(&mut *state.redis.get()?)?;
+    let (server_seed, nonce) = session.ok_or_else(fair_session_not_found)?;
+    Ok(json!({
+        "server_seed": server_seed,
+        "commitment": provably_fair::commitment(&server_seed),
+        "rolls": nonce,
+    })
+    .into())
+}
+
+fn encounter_key(req: &Request<State>) -> tide::Result<String> {
+    Ok(format!(
+        "{}:{}",
+        REDIS_KEY_ENCOUNTER,
+        req.param("encounter")?
+    ))
+}
+
+fn encounter_not_found() -> tide::Error {
+    tide::Error::from_str(StatusCode::NotFound, "No encounter with that id.")
+}
+
+/// Change an encounter in place. Redis watches the key, so if another device changes
+/// the encounter in the meantime the change is made again on top of theirs.
+fn update_encounter<T>(
+    state: &State,
+    key: &str,
+    mut change: impl FnMut(&mut Encounter) -> Result<T, RollError>,
+) -> tide::Result<(Encounter, T)> {
+    let mut conn = state.redis.get()?;
+    let updated = redis::transaction(&mut *conn, &[key], |con, pipe| {
+        let json: Option<String> = con.get(key)?;
+        let mut encounter: Encounter = match json.as_deref().map(serde_json::from_str) {
+            Some(Ok(encounter)) => encounter,
+            Some(Err(_)) => {
+                return Err((redis::ErrorKind::TypeError, "Invalid encounter").into());
+            }
+            None => return Ok(Some(None)),
+        };
+        let value = match change(&mut encounter) {
+            Ok(value) => value,
+            // Nothing to write, so give up the watch without retrying
+            Err(e) => return Ok(Some(Some(Err(e)))),
+        };
+        let json = serde_json::to_string(&encounter)
+            .map_err(|_| (redis::ErrorKind::TypeError, "Invalid encounter"))?;
+        let written: Option<()> = pipe
+            .set_ex(key, json, ENCOUNTER_SECONDS)
+            .ignore()
+            .query(con)?;
+        Ok(written.map(|()| Some(Ok((encounter, value)))))
+    })?;
+    Ok(updated.ok_or_else(encounter_not_found)??)
+}
+
+/// Start tracking a new encounter
+pub async fn new_encounter(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let encounter = Encounter::new(random_id());
+    let key = format!("{}:{}", REDIS_KEY_ENCOUNTER, encounter.id);
+    state.redis.get()?.set_ex::<_, _, ()>(
+        &key,
+        serde_json::to_string(&encounter)?,
+        ENCOUNTER_SECONDS,
+    )?;
+    let mut res = Response::new(StatusCode::Created);
+    res.set_body(json!(&encounter));
+    Ok(res)
+}
+
+pub async fn get_encounter(req: Request<State>) -> tide::Result {
+    let key = encounter_key(&req)?;
+    let json: Option<String> = req.state().redis.get()?.get(&key)?;
+    let encounter: Encounter = serde_json::from_str(&json.ok_or_else(encounter_not_found)?)?;
+    Ok(json!(&encounter).into())
+}
+
+pub async fn delete_encounter(req: Request<State>) -> tide::Result {
+    let key = encounter_key(&req)?;
+    let deleted: bool = req.state().redis.get()?.del(&key)?;
+    if !deleted {
+        return Err(encounter_not_found());
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+pub async fn add_combatant(mut req: Request<State>) -> tide::Result {
+    let body: NewCombatant = req.body_json().await?;
+    let key = encounter_key(&req)?;
+    let (encounter, ()) = update_encounter(req.state(), &key, |encounter| {
+        encounter.add_combatant(body.clone());
+        Ok(())
+    })?;
+    Ok(json!(&encounter).into())
+}
+
+pub async fn remove_combatant(req: Request<State>) -> tide::Result {
+    let key = encounter_key(&req)?;
+    let id: u32 = req.param("combatant")?.parse()?;
+    let (encounter, _) = update_encounter(req.state(), &key, |encounter| {
+        encounter.remove_combatant(id)
+    })?;
+    Ok(json!(&encounter).into())
+}
+
+/// Roll initiative for anyone who hasn't got it, starting the encounter if it hasn't started
+pub async fn roll_initiative(req: Request<State>) -> tide::Result {
+    let key = encounter_key(&req)?;
+    let state = req.state();
+    let mut rng = SeededRng::from_entropy();
+    let (encounter, rolls) = update_encounter(state, &key, |encounter| {
+        encounter.roll_initiative(&mut rng, state.limits)
+    })?;
+    roll_stats(
+        state,
+        &stat_scopes(req.ext(), None),
+        rolls.iter().flat_map(|(_, r)| &r.terms),
+    );
+    record_rolls(state, req.ext(), false, rolls.iter().map(|(_, r)| r))?;
+    let rolls: Vec<_> = rolls
+        .into_iter()
+        .map(|(combatant, result)| json!({ "combatant": combatant, "result": result }))
+        .collect();
+    Ok(json!({ "encounter": encounter, "rolls": rolls }).into())
+}
+
+/// Move on to the next turn, returning any effects that wore off
+pub async fn next_turn(req: Request<State>) -> tide::Result {
+    let key = encounter_key(&req)?;
+    let (encounter, expired) = update_encounter(req.state(), &key, Encounter::next_turn)?;
+    Ok(json!({ "encounter": encounter, "expired": expired }).into())
+}
+
+pub async fn add_duration(mut req: Request<State>) -> tide::Result {
+    let body: NewDuration = req.body_json().await?;
+    let key = encounter_key(&req)?;
+    let (encounter, ()) = update_encounter(req.state(), &key, |encounter| {
+        encounter.add_duration(body.clone()).map(|_| ())
+    })?;
+    Ok(json!(&encounter).into())
+}
+
+const fn error_status(kind: RollErrorKind) -> StatusCode {
+    match kind {
+        RollErrorKind::Syntax => StatusCode::BadRequest,
+        RollErrorKind::InvalidDie
+        | RollErrorKind::DiceCount
+        | RollErrorKind::Endless
+        | RollErrorKind::Overflow
+        | RollErrorKind::DivideByZero
+        | RollErrorKind::TooComplex
+        | RollErrorKind::Unsupported
+        | RollErrorKind::InvalidCritRange
+        | RollErrorKind::InvalidAbilityScores
+        | RollErrorKind::InvalidEncounter => StatusCode::UnprocessableEntity,
+    }
+}
+
+/// Turn roll errors into a JSON problem body with a matching status, rather than a 500
+pub async fn roll_error_response(mut res: Response) -> tide::Result {
+    if let Some(err) = res.downcast_error::<RollError>() {
+        let status = error_status(err.kind);
+        let body = json!({
+            "type": format!("/errors/{}", err.code()),
+            "title": err.message,
+            "status": status as u16,
+            "detail": err.to_string(),
+            "code": err.code(),
+            "span": err.span,
+            "suggestions": err.suggestions,
+        });
+        res.set_status(status);
+        res.set_body(body);
+        res.set_content_type("application/problem+json");
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use d20::dice_roller::RollLimits;
+
+    #[test]
+    fn test_history_rows() {
+        let user = Claims {
+            sub: 7,
+            username: String::from("tav"),
+            exp: 0,
+        };
+        let attack = Attack {
+            bonus: 0,
+            armor_class: -100,
+            damage: Some(String::from("1d6")),
+            advantage: false,
+            disadvantage: false,
+            crit_range: None,
+        };
+        let mut rng = SeededRng::new(3);
+        // Anything but a natural 1 hits
+        let result =
+            iter::repeat_with(|| checks::attack(&mut rng, &attack, RollLimits::default()).unwrap())
+                .find(|result| result.damage.is_some())
+                .unwrap();
+
+        let rows = history_rows(Some(&user), None, false, false, result.results());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].instruction, result.roll.instruction);
+        assert_eq!(rows[1].instruction, "1d6");
+        assert_eq!(rows[1].total, result.damage.as_ref().unwrap().total);
+        for row in &rows {
+            assert_eq!(row.user_id, Some(7));
+            assert_eq!(row.user_name, Some("tav"));
+            assert_eq!(row.room, None);
+            assert!(!row.private && !row.seeded);
+        }
+    }
 }
@@ -1,29 +1,46 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic)]
 #![allow(clippy::used_underscore_binding)]
-use async_std::io;
-use d20::{r2d2_rng::RngConnectionManager, redis_pool, rng_pool, sentry_init};
-use diesel::r2d2::Pool;
+use async_std::{channel, io, task};
+use d20::{db_pool, dice_roller::RollLimits, jwt_secret, redis_pool, roll_limits, sentry_init};
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, Pool},
+};
 use dotenv::dotenv;
+use flusher::Flusher;
 use r2d2_redis::RedisConnectionManager;
-use std::env;
-use tide::{security::CorsMiddleware, Server};
+use rooms::Rooms;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{env, future::Future, sync::Arc, thread, time::Duration};
+use tide::{security::CorsMiddleware, sse, utils::After, Server};
 
+mod flusher;
 mod handlers;
+mod rooms;
 
 // First, we define `State` that holds accumulator state. This is accessible as state in
 // Tide, and as executor context in Juniper.
 #[derive(Clone)]
 pub struct State {
+    db: Pool<ConnectionManager<PgConnection>>,
+    jwt_secret: Arc<[u8]>,
+    limits: RollLimits,
     redis: Pool<RedisConnectionManager>,
-    rng: Pool<RngConnectionManager>,
+    rooms: Rooms,
 }
 
 impl Default for State {
     #[must_use]
     fn default() -> Self {
         Self {
+            db: db_pool(),
+            jwt_secret: jwt_secret().into(),
+            limits: roll_limits(),
             redis: redis_pool(),
-            rng: rng_pool(),
+            rooms: Rooms::default(),
         }
     }
 }
@@ -40,17 +57,99 @@ async fn main() -> io::Result<()> {
         .parse()
         .expect("PORT must be a number");
 
+    // Flushing roll stats from here is opt in, by setting how often to do it
+    let flush_seconds: u64 = env::var("STATS_FLUSH_SECONDS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .expect("STATS_FLUSH_SECONDS must be a number");
+
+    let state = State::default();
+    let flusher = (flush_seconds > 0).then(|| {
+        Flusher::start(
+            state.db.clone(),
+            state.redis.clone(),
+            Duration::from_secs(flush_seconds),
+        )
+    });
+
     // Start a server, configuring the resources to serve.
-    let mut app = Server::with_state(State::default());
+    let mut app = Server::with_state(state);
 
-    app.with(CorsMiddleware::new());
+    app.with(CorsMiddleware::new())
+        .with(After(handlers::roll_error_response))
+        .with(handlers::authenticate);
     //     .with(Compression::new())
     //     .with(Decompression::new());
 
+    app.at("/users/").post(handlers::signup);
+    app.at("/users/me").get(handlers::current_user);
+    app.at("/users/me/stats").get(handlers::user_stats);
+    app.at("/login").post(handlers::login);
     app.at("/roll/")
         .get(handlers::parse_roll)
         .post(handlers::roll);
+    app.at("/roll/batch").post(handlers::roll_batch);
+    app.at("/roll/stats").get(handlers::roll_odds);
+    app.at("/roll/history").get(handlers::roll_history);
+    app.at("/roll/replay").get(handlers::replay_roll);
+    app.at("/attack").post(handlers::attack);
+    app.at("/save").post(handlers::save);
+    app.at("/character/abilities").post(handlers::abilities);
+    app.at("/fair/").post(handlers::new_fair_session);
+    app.at("/fair/:session/roll").post(handlers::fair_roll);
+    app.at("/fair/:session/reveal")
+        .post(handlers::reveal_fair_session);
+    app.at("/rooms/:room")
+        .get(sse::endpoint(handlers::join_room))
+        .post(handlers::open_room);
+    app.at("/rooms/:room/gms").post(handlers::add_room_gm);
+    app.at("/rooms/:room/gms/:username")
+        .delete(handlers::remove_room_gm);
+    app.at("/rooms/:room/roll").post(handlers::room_roll);
+    app.at("/rooms/:room/stats").get(handlers::room_stats);
+    app.at("/stats").get(handlers::all_roll_stats);
+    app.at("/stats/advantage")
+        .get(handlers::advantage_roll_stats);
+    app.at("/stats/fairness").get(handlers::fairness);
+    app.at("/stats/:die").get(handlers::die_roll_stats);
+    app.at("/encounters/").post(handlers::new_encounter);
+    app.at("/encounters/:encounter")
+        .get(handlers::get_encounter)
+        .delete(handlers::delete_encounter);
+    app.at("/encounters/:encounter/combatants")
+        .post(handlers::add_combatant);
+    app.at("/encounters/:encounter/combatants/:combatant")
+        .delete(handlers::remove_combatant);
+    app.at("/encounters/:encounter/initiative")
+        .post(handlers::roll_initiative);
+    app.at("/encounters/:encounter/next")
+        .post(handlers::next_turn);
+    app.at("/encounters/:encounter/durations")
+        .post(handlers::add_duration);
 
-    app.listen(format!("0.0.0.0:{}", port)).await?;
-    Ok(())
+    let listener = app.listen(format!("0.0.0.0:{}", port));
+    match flusher {
+        Some(flusher) => shut_down_gracefully(listener, flusher).await,
+        None => listener.await,
+    }
+}
+
+/// Serve until SIGTERM or SIGINT, then give the flusher a chance to flush one last time
+async fn shut_down_gracefully(
+    listener: impl Future<Output = io::Result<()>> + Send + 'static,
+    flusher: Flusher,
+) -> io::Result<()> {
+    let (done, stopped) = channel::bounded(1);
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let on_signal = done.clone();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            tide::log::info!("Shutting down on signal {}", signal);
+            task::block_on(on_signal.send(Ok(()))).ok();
+        }
+    });
+    task::spawn(async move { done.send(listener.await).await.ok() });
+    let result = stopped.recv().await.unwrap_or(Ok(()));
+    flusher.stop();
+    result
 }
@@ -0,0 +1,84 @@
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// Rolls that can queue up for a member before they start missing them
+const MEMBER_BUFFER: usize = 32;
+
+/// A roll shared with everyone in a room
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomRoll {
+    #[serde(skip)]
+    pub user_id: i32,
+    /// Who made the roll
+    pub name: String,
+    /// Only shown to the GM and the roller
+    pub private: bool,
+    pub result: Value,
+}
+
+impl RoomRoll {
+    const fn visible_to(&self, member: &Member) -> bool {
+        let own_roll = member.user_id == self.user_id;
+        !self.private || member.gm || own_roll
+    }
+}
+
+struct Member {
+    user_id: i32,
+    gm: bool,
+    sender: Sender<RoomRoll>,
+}
+
+/// Everyone listening in each room
+#[derive(Clone, Default)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<String, Vec<Member>>>>,
+}
+
+impl Rooms {
+    /// Join a room, receiving every roll made in it from now on.
+    /// Whether the member is a GM is up to the caller to check.
+    pub fn join(&self, room: &str, user_id: i32, gm: bool) -> Receiver<RoomRoll> {
+        let (sender, receiver) = channel::bounded(MEMBER_BUFFER);
+        self.rooms
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry(room.to_string())
+            .or_default()
+            .push(Member {
+                user_id,
+                gm,
+                sender,
+            });
+        receiver
+    }
+
+    /// Send a roll to everyone in the room who can see it
+    pub fn broadcast(&self, room: &str, roll: &RoomRoll) {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(members) = rooms.get_mut(room) {
+            // Anyone who has left is dropped, but a slow member just misses the roll
+            members.retain(|member| {
+                if roll.visible_to(member) {
+                    !matches!(
+                        member.sender.try_send(roll.clone()),
+                        Err(TrySendError::Closed(_))
+                    )
+                } else {
+                    !member.sender.is_closed()
+                }
+            });
+            if members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+}
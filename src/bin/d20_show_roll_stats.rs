@@ -1,19 +1,121 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic)]
 #![allow(clippy::used_underscore_binding)]
-use d20::{db_pool, models::RollStat, schema::roll_stats, sentry_init};
+use d20::{
+    db_pool,
+    fairness::{self, FaceCount, FairnessReport, TestResult},
+    models::RollStat,
+    schema::roll_stats,
+    sentry_init, sentry_init_quiet,
+};
 use diesel::prelude::*;
 use dotenv::dotenv;
-use std::error::Error;
+use serde_json::json;
+use std::{convert::TryFrom, env, error::Error, process, str::FromStr};
+
+const USAGE: &str = "Usage: d20_show_roll_stats [--format text|json|csv|histogram] [--die SIDES]";
+// Widest bar in a histogram, for the face that came up most
+const BAR_WIDTH: usize = 40;
+
+/// How to print the stats
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Format {
+    /// A line per stat, then the fairness reports
+    Text,
+    /// The stats and fairness reports in one object
+    Json,
+    /// A row per stat, with a header
+    Csv,
+    /// A bar per face, grouped by die
+    Histogram,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            "histogram" => Ok(Self::Histogram),
+            _ => Err(format!("Unknown format {}\n{}", s, USAGE)),
+        }
+    }
+}
+
+struct Options {
+    format: Format,
+    die: Option<i16>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self {
+            format: Format::Text,
+            die: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--format" | "-f" => options.format = value()?.parse()?,
+                "--die" | "-d" => {
+                    let die = value()?;
+                    // Allow d20 as well as 20
+                    let sides = die.strip_prefix('d').unwrap_or(&die);
+                    options.die =
+                        Some(sides.parse().map_err(|_| {
+                            format!("--die should be a number of sides, not {}", die)
+                        })?);
+                }
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ => return Err(format!("Unknown argument {}\n{}", arg, USAGE)),
+            }
+        }
+        Ok(options)
+    }
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
-    let _guard = sentry_init();
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+    // Keep the log out of output that gets piped elsewhere
+    let _guard = match options.format {
+        Format::Text | Format::Histogram => sentry_init(),
+        Format::Json | Format::Csv => sentry_init_quiet(),
+    };
     let pool = db_pool();
     let connection = pool.get()?;
-    let results = roll_stats::table
+    let mut query = roll_stats::table
+        .order((roll_stats::die, roll_stats::roll))
+        .into_boxed();
+    if let Some(die) = options.die {
+        query = query.filter(roll_stats::die.eq(die));
+    }
+    let results = query
         .load::<RollStat>(&connection)
         .expect("Error loading stats");
+    let reports = fairness::fairness_reports(&connection, options.die)?;
+
+    match options.format {
+        Format::Text => print_text(&results, &reports),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({ "stats": results, "fairness": reports }))?
+        ),
+        Format::Csv => print_csv(&results),
+        Format::Histogram => print_histogram(&reports),
+    }
 
+    Ok(())
+}
+
+fn print_text(results: &[RollStat], reports: &[FairnessReport]) {
     println!("Displaying {} stats", results.len());
     for stat in results {
         println!(
@@ -22,5 +124,84 @@ fn main() -> Result<(), Box<dyn Error>> {
         );
     }
 
-    Ok(())
+    for report in reports {
+        println!();
+        println!(
+            "d{} fairness over {} rolls{}",
+            report.die,
+            report.total,
+            if report.biased { " - BIASED" } else { "" }
+        );
+        for face in &report.faces {
+            println!(
+                "  {:3}: {:10} observed, {:12.1} expected",
+                face.roll, face.observed, face.expected
+            );
+        }
+        print_test("Chi-square", report.chi_square);
+        print_test("Kolmogorov-Smirnov", report.kolmogorov_smirnov);
+        print_test("Runs", report.runs);
+    }
+}
+
+fn print_test(name: &str, test: Option<TestResult>) {
+    match test {
+        Some(test) => println!(
+            "  {}: statistic {:.4}, p-value {:.4}",
+            name, test.statistic, test.p_value
+        ),
+        None => println!("  {}: not enough rolls", name),
+    }
+}
+
+fn print_csv(results: &[RollStat]) {
+    println!("die,roll,roll_count,updated_at");
+    for stat in results {
+        println!(
+            "{},{},{},{}",
+            stat.die, stat.roll, stat.roll_count, stat.updated_at
+        );
+    }
+}
+
+fn print_histogram(reports: &[FairnessReport]) {
+    for (i, report) in reports.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "d{} ({} rolls){}",
+            report.die,
+            report.total,
+            if report.biased { " - BIASED" } else { "" }
+        );
+        // Counts are never negative
+        let count = |face: &FaceCount| usize::try_from(face.observed).unwrap_or(0);
+        let most = report.faces.iter().map(count).max().unwrap_or(0);
+        for face in &report.faces {
+            let width = (count(face) * BAR_WIDTH).checked_div(most).unwrap_or(0);
+            let bar = "#".repeat(width);
+            if face.expected > 0.0 {
+                // Roll counts are nowhere near big enough to lose precision as floats
+                #[allow(clippy::cast_precision_loss)]
+                let deviation = (face.observed as f64 - face.expected) / face.expected * 100.0;
+                println!(
+                    "{:4} | {:<width$} {:8} ({:+.1}%)",
+                    face.roll,
+                    bar,
+                    face.observed,
+                    deviation,
+                    width = BAR_WIDTH
+                );
+            } else {
+                println!(
+                    "{:4} | {:<width$} {:8}",
+                    face.roll,
+                    bar,
+                    face.observed,
+                    width = BAR_WIDTH
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,311 @@
+//! Ability scores for new characters, by any of the usual 5e methods
+use crate::{
+    dice_roller::{
+        self, DiceTerm, Expression, RollError, RollErrorKind, RollLimits, RollResult, Selection,
+    },
+    r2d2_rng::SeededRng,
+};
+use serde::{Deserialize, Serialize};
+
+const STANDARD_ARRAY: [i32; 6] = [15, 14, 13, 12, 10, 8];
+const POINT_BUY_BUDGET: i32 = 27;
+
+/// One score for each ability
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AbilityScores {
+    pub strength: i32,
+    pub dexterity: i32,
+    pub constitution: i32,
+    pub intelligence: i32,
+    pub wisdom: i32,
+    pub charisma: i32,
+}
+
+impl AbilityScores {
+    #[must_use]
+    pub const fn from_array(scores: [i32; 6]) -> Self {
+        let [strength, dexterity, constitution, intelligence, wisdom, charisma] = scores;
+        Self {
+            strength,
+            dexterity,
+            constitution,
+            intelligence,
+            wisdom,
+            charisma,
+        }
+    }
+
+    #[must_use]
+    pub const fn to_array(self) -> [i32; 6] {
+        [
+            self.strength,
+            self.dexterity,
+            self.constitution,
+            self.intelligence,
+            self.wisdom,
+            self.charisma,
+        ]
+    }
+
+    /// Modifier for each of the scores
+    #[must_use]
+    pub fn modifiers(self) -> Self {
+        Self::from_array(self.to_array().map(modifier))
+    }
+}
+
+/// Modifier for an ability score, such as +2 for a 14 or -1 for a 9
+#[must_use]
+pub const fn modifier(score: i32) -> i32 {
+    score.saturating_sub(10).div_euclid(2)
+}
+
+/// Ways of coming up with a set of ability scores
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(tag = "method")]
+pub enum Method {
+    /// Roll 4d6 six times, dropping the lowest die each time
+    #[serde(rename = "4d6_drop_lowest")]
+    FourD6DropLowest,
+    /// Roll 3d6 for each ability, in order
+    #[serde(rename = "3d6_in_order")]
+    ThreeD6InOrder,
+    /// 15, 14, 13, 12, 10 and 8, in any order. In the order listed if none is given.
+    #[serde(rename = "standard_array")]
+    StandardArray { scores: Option<AbilityScores> },
+    /// Scores from 8 to 15, bought with 27 points
+    #[serde(rename = "point_buy")]
+    PointBuy { scores: AbilityScores },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Abilities {
+    pub scores: AbilityScores,
+    pub modifiers: AbilityScores,
+    /// The roll for each score, if they were rolled
+    pub rolls: Vec<RollResult>,
+    /// Points spent, if the scores were bought
+    pub points_spent: Option<i32>,
+}
+
+impl Abilities {
+    fn new(scores: AbilityScores) -> Self {
+        Self {
+            scores,
+            modifiers: scores.modifiers(),
+            rolls: Vec::new(),
+            points_spent: None,
+        }
+    }
+}
+
+fn invalid(message: &str) -> RollError {
+    RollError::new(RollErrorKind::InvalidAbilityScores, message)
+}
+
+/// Points a single score costs with point buy
+const fn point_cost(score: i32) -> Option<i32> {
+    match score {
+        8..=13 => Some(score - 8),
+        14 => Some(7),
+        15 => Some(9),
+        _ => None,
+    }
+}
+
+/// Total cost of a set of scores with point buy, as long as they are allowed
+///
+/// # Errors
+///
+/// Will return `RollError` if any score is outside 8 to 15, or they cost more than 27 points
+pub fn point_buy_cost(scores: AbilityScores) -> Result<i32, RollError> {
+    let mut total = 0;
+    for score in scores.to_array() {
+        total += point_cost(score)
+            .ok_or_else(|| invalid("Point buy scores have to be from 8 to 15."))?;
+    }
+    if total > POINT_BUY_BUDGET {
+        return Err(invalid(&format!(
+            "Those scores cost {} points, but there are only {} to spend.",
+            total, POINT_BUY_BUDGET
+        )));
+    }
+    Ok(total)
+}
+
+/// Roll one score for each ability
+fn roll_scores(
+    rng: &mut SeededRng,
+    dice: &Expression,
+    limits: RollLimits,
+) -> Result<Abilities, RollError> {
+    let mut rolls = Vec::new();
+    for _ in 0..6 {
+        rolls.push(dice_roller::roll_seeded(rng, dice, limits)?);
+    }
+    let mut scores = [0; 6];
+    for (score, roll) in scores.iter_mut().zip(&rolls) {
+        *score = roll.total;
+    }
+    let mut abilities = Abilities::new(AbilityScores::from_array(scores));
+    abilities.rolls = rolls;
+    Ok(abilities)
+}
+
+/// Come up with a set of ability scores
+///
+/// # Errors
+///
+/// Will return `RollError` if scores given for the standard array or point buy aren't allowed
+pub fn generate(
+    rng: &mut SeededRng,
+    method: Method,
+    limits: RollLimits,
+) -> Result<Abilities, RollError> {
+    match method {
+        Method::FourD6DropLowest => {
+            let dice = Expression::Dice(DiceTerm {
+                selection: Some(Selection::DropLowest(1)),
+                ..DiceTerm::new(4, 6)
+            });
+            roll_scores(rng, &dice, limits)
+        }
+        Method::ThreeD6InOrder => roll_scores(rng, &Expression::Dice(DiceTerm::new(3, 6)), limits),
+        Method::StandardArray { scores } => {
+            let scores = scores.unwrap_or_else(|| AbilityScores::from_array(STANDARD_ARRAY));
+            let mut sorted = scores.to_array();
+            sorted.sort_unstable_by(|a, b| b.cmp(a));
+            if sorted != STANDARD_ARRAY {
+                return Err(invalid(
+                    "The standard array is 15, 14, 13, 12, 10 and 8, once each.",
+                ));
+            }
+            Ok(Abilities::new(scores))
+        }
+        Method::PointBuy { scores } => {
+            // Scores come from clients, so check them before working out modifiers
+            let points_spent = point_buy_cost(scores)?;
+            let mut abilities = Abilities::new(scores);
+            abilities.points_spent = Some(points_spent);
+            Ok(abilities)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifier() {
+        assert_eq!(modifier(1), -5);
+        assert_eq!(modifier(8), -1);
+        assert_eq!(modifier(9), -1);
+        assert_eq!(modifier(10), 0);
+        assert_eq!(modifier(11), 0);
+        assert_eq!(modifier(15), 2);
+        assert_eq!(modifier(20), 5);
+        assert_eq!(modifier(i32::MIN), i32::MIN / 2);
+    }
+
+    #[test]
+    fn test_four_d6_drop_lowest() {
+        let mut rng = SeededRng::new(15);
+        let abilities =
+            generate(&mut rng, Method::FourD6DropLowest, RollLimits::default()).unwrap();
+        assert_eq!(abilities.rolls.len(), 6);
+        for (score, roll) in abilities.scores.to_array().iter().zip(&abilities.rolls) {
+            assert!((3..=18).contains(score));
+            assert_eq!(roll.instruction, "4d6dl1");
+            assert_eq!(roll.rolls.len(), 4);
+        }
+        assert_eq!(abilities.modifiers, abilities.scores.modifiers());
+
+        // The same seed gives the same scores
+        let again = generate(
+            &mut SeededRng::new(15),
+            Method::FourD6DropLowest,
+            RollLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(again.scores, abilities.scores);
+    }
+
+    #[test]
+    fn test_three_d6_in_order() {
+        let mut rng = SeededRng::new(15);
+        let abilities = generate(&mut rng, Method::ThreeD6InOrder, RollLimits::default()).unwrap();
+        let totals: Vec<i32> = abilities.rolls.iter().map(|r| r.total).collect();
+        assert_eq!(totals, abilities.scores.to_array());
+    }
+
+    #[test]
+    fn test_standard_array() {
+        let mut rng = SeededRng::new(15);
+        let abilities = generate(
+            &mut rng,
+            Method::StandardArray { scores: None },
+            RollLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(abilities.scores.to_array(), STANDARD_ARRAY);
+        assert_eq!(abilities.modifiers.to_array(), [2, 2, 1, 1, 0, -1]);
+
+        let scores = AbilityScores::from_array([8, 10, 12, 13, 14, 15]);
+        let method = Method::StandardArray {
+            scores: Some(scores),
+        };
+        assert_eq!(
+            generate(&mut rng, method, RollLimits::default())
+                .unwrap()
+                .scores,
+            scores
+        );
+        let method = Method::StandardArray {
+            scores: Some(AbilityScores::from_array([15, 15, 13, 12, 10, 8])),
+        };
+        assert!(generate(&mut rng, method, RollLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_point_buy() {
+        assert_eq!(
+            point_buy_cost(AbilityScores::from_array([15, 15, 15, 8, 8, 8])),
+            Ok(27)
+        );
+        assert_eq!(
+            point_buy_cost(AbilityScores::from_array([8, 8, 8, 8, 8, 8])),
+            Ok(0)
+        );
+        assert!(point_buy_cost(AbilityScores::from_array([15, 15, 15, 9, 8, 8])).is_err());
+        assert!(point_buy_cost(AbilityScores::from_array([16, 8, 8, 8, 8, 8])).is_err());
+        assert!(point_buy_cost(AbilityScores::from_array([7, 8, 8, 8, 8, 8])).is_err());
+
+        let mut rng = SeededRng::new(15);
+        let scores = AbilityScores::from_array([14, 14, 14, 12, 10, 8]);
+        let abilities =
+            generate(&mut rng, Method::PointBuy { scores }, RollLimits::default()).unwrap();
+        assert_eq!(abilities.points_spent, Some(27));
+        assert!(abilities.rolls.is_empty());
+
+        let scores = AbilityScores::from_array([i32::MIN, 8, 8, 8, 8, i32::MAX]);
+        assert!(generate(&mut rng, Method::PointBuy { scores }, RollLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_method_json() {
+        let method: Method = serde_json::from_str(r#"{"method": "4d6_drop_lowest"}"#).unwrap();
+        assert_eq!(method, Method::FourD6DropLowest);
+        let method: Method = serde_json::from_str(
+            r#"{"method": "point_buy", "scores": {"strength": 15, "dexterity": 14,
+            "constitution": 13, "intelligence": 12, "wisdom": 10, "charisma": 8}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            method,
+            Method::PointBuy {
+                scores: AbilityScores::from_array(STANDARD_ARRAY)
+            }
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Attack rolls against Armor Class and saving throws against a DC, following the 5e rules
+use crate::{
+    dice_roller::{
+        self, Advantage, CritRange, DiceTerm, Expression, Operator, RollError, RollLimits,
+        RollResult,
+    },
+    r2d2_rng::SeededRng,
+};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, iter};
+
+/// An attack roll, `1d20 + bonus`, against a target's Armor Class
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Attack {
+    /// Added to the d20
+    pub bonus: i32,
+    pub armor_class: i32,
+    /// Damage to roll on a hit, such as `1d8 + 3`
+    pub damage: Option<String>,
+    #[serde(default)]
+    pub advantage: bool,
+    #[serde(default)]
+    pub disadvantage: bool,
+    /// Lowest natural roll that crits. Just 20 if not given.
+    pub crit_range: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackOutcome {
+    Hit,
+    Miss,
+    /// A natural roll in the crit range, which always hits and doubles the damage dice
+    CriticalHit,
+    /// A natural 1, which always misses
+    CriticalMiss,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttackResult {
+    pub outcome: AttackOutcome,
+    /// The attack roll
+    pub roll: RollResult,
+    /// Damage dealt, if the attack hit and had damage to roll
+    pub damage: Option<RollResult>,
+}
+
+impl AttackResult {
+    /// The attack roll, then the damage if any was rolled
+    pub fn results(&self) -> impl Iterator<Item = &RollResult> {
+        iter::once(&self.roll).chain(&self.damage)
+    }
+}
+
+/// A saving throw, `1d20 + modifier`, against a DC
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Save {
+    /// Added to the d20
+    pub modifier: i32,
+    pub dc: i32,
+    /// Damage the save is against, such as `8d6` for a fireball
+    pub damage: Option<String>,
+    /// Whether a successful save takes half damage, rather than none
+    #[serde(default)]
+    pub half_on_success: bool,
+    #[serde(default)]
+    pub advantage: bool,
+    #[serde(default)]
+    pub disadvantage: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SaveResult {
+    pub success: bool,
+    /// The saving throw
+    pub roll: RollResult,
+    /// The damage rolled, before halving
+    pub damage: Option<RollResult>,
+    /// Damage actually taken once the save is accounted for
+    pub damage_taken: Option<i32>,
+}
+
+impl SaveResult {
+    /// The saving throw, then the damage if any was rolled
+    pub fn results(&self) -> impl Iterator<Item = &RollResult> {
+        iter::once(&self.roll).chain(&self.damage)
+    }
+}
+
+/// `1d20 + modifier`, rolled with advantage or disadvantage if there is any
+pub(crate) fn d20(modifier: i32, advantage: bool, disadvantage: bool) -> Expression {
+    let advantage = match (advantage, disadvantage) {
+        (true, true) => Some(Advantage::Cancelled),
+        (true, false) => Some(Advantage::Advantage(2)),
+        (false, true) => Some(Advantage::Disadvantage),
+        (false, false) => None,
+    };
+    let d20 = Expression::Dice(DiceTerm {
+        advantage,
+        ..DiceTerm::new(1, 20)
+    });
+    match modifier.cmp(&0) {
+        Ordering::Equal => d20,
+        Ordering::Less => Expression::binary(
+            Operator::Subtract,
+            d20,
+            Expression::Number(modifier.saturating_neg()),
+        ),
+        Ordering::Greater => Expression::binary(Operator::Add, d20, Expression::Number(modifier)),
+    }
+}
+
+/// Roll an attack, and its damage if it hits
+///
+/// # Errors
+///
+/// Will return `RollError` if the damage or crit range are invalid
+pub fn attack(
+    rng: &mut SeededRng,
+    attack: &Attack,
+    limits: RollLimits,
+) -> Result<AttackResult, RollError> {
+    let crit_range = attack
+        .crit_range
+        .map_or_else(|| Ok(CritRange::default()), CritRange::new)?;
+    // Check the damage before rolling anything
+    let damage = attack
+        .damage
+        .as_deref()
+        .map(dice_roller::parse_roll)
+        .transpose()?;
+
+    let mut roll = dice_roller::roll_seeded(
+        rng,
+        &d20(attack.bonus, attack.advantage, attack.disadvantage),
+        limits,
+    )?;
+    roll.set_crit_range(crit_range);
+    let outcome = if roll.critical {
+        AttackOutcome::CriticalHit
+    } else if roll.fumble {
+        AttackOutcome::CriticalMiss
+    } else if roll.total >= attack.armor_class {
+        AttackOutcome::Hit
+    } else {
+        AttackOutcome::Miss
+    };
+
+    let damage = match (outcome, damage) {
+        (AttackOutcome::CriticalHit, Some(damage)) => Some(dice_roller::roll_seeded(
+            rng,
+            &damage.crit_damage(),
+            limits,
+        )?),
+        (AttackOutcome::Hit, Some(damage)) => Some(dice_roller::roll_seeded(rng, &damage, limits)?),
+        _ => None,
+    };
+    Ok(AttackResult {
+        outcome,
+        roll,
+        damage,
+    })
+}
+
+/// Roll a saving throw, and the damage it is against
+///
+/// # Errors
+///
+/// Will return `RollError` if the damage is invalid
+pub fn save(rng: &mut SeededRng, save: &Save, limits: RollLimits) -> Result<SaveResult, RollError> {
+    let damage = save
+        .damage
+        .as_deref()
+        .map(dice_roller::parse_roll)
+        .transpose()?;
+
+    // Unlike attacks, a natural 20 or 1 is nothing special on a save
+    let roll = dice_roller::roll_seeded(
+        rng,
+        &d20(save.modifier, save.advantage, save.disadvantage),
+        limits,
+    )?;
+    let success = roll.total >= save.dc;
+
+    let damage = damage
+        .map(|damage| dice_roller::roll_seeded(rng, &damage, limits))
+        .transpose()?;
+    let damage_taken = damage.as_ref().map(|damage| match success {
+        false => damage.total,
+        true if save.half_on_success => damage.total.div_euclid(2),
+        true => 0,
+    });
+    Ok(SaveResult {
+        success,
+        roll,
+        damage,
+        damage_taken,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_attack() -> Attack {
+        Attack {
+            bonus: 5,
+            armor_class: 15,
+            damage: Some(String::from("1d8 + 3")),
+            advantage: false,
+            disadvantage: false,
+            crit_range: None,
+        }
+    }
+
+    #[test]
+    fn test_d20() {
+        assert_eq!(d20(5, false, false).to_string(), "1d20 + 5");
+        assert_eq!(d20(-1, true, false).to_string(), "1d20adv - 1");
+        assert_eq!(d20(0, false, true).to_string(), "1d20dis");
+        assert_eq!(d20(2, true, true).to_string(), "1d20advdis + 2");
+    }
+
+    #[test]
+    fn test_attack() {
+        let mut rng = SeededRng::new(14);
+        let attack = base_attack();
+        for _ in 0..200 {
+            let result = super::attack(&mut rng, &attack, RollLimits::default()).unwrap();
+            let natural = result.roll.rolls[0];
+            let expected = match natural {
+                20 => AttackOutcome::CriticalHit,
+                1 => AttackOutcome::CriticalMiss,
+                n if n + 5 >= 15 => AttackOutcome::Hit,
+                _ => AttackOutcome::Miss,
+            };
+            assert_eq!(result.outcome, expected);
+            assert_eq!(
+                result.results().count(),
+                1 + usize::from(result.damage.is_some())
+            );
+            match result.outcome {
+                AttackOutcome::Hit => {
+                    let damage = result.damage.unwrap();
+                    assert_eq!(damage.instruction, "1d8 + 3");
+                    assert!((4..=11).contains(&damage.total));
+                }
+                AttackOutcome::CriticalHit => {
+                    assert_eq!(result.damage.unwrap().instruction, "2d8 + 3");
+                }
+                AttackOutcome::Miss | AttackOutcome::CriticalMiss => {
+                    assert!(result.damage.is_none());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_attack_crit_range() {
+        let mut rng = SeededRng::new(14);
+        let attack = Attack {
+            armor_class: 100,
+            crit_range: Some(19),
+            ..base_attack()
+        };
+        for _ in 0..100 {
+            let result = super::attack(&mut rng, &attack, RollLimits::default()).unwrap();
+            let crit = result.roll.rolls[0] >= 19;
+            assert_eq!(result.outcome == AttackOutcome::CriticalHit, crit);
+            assert_eq!(result.damage.is_some(), crit);
+        }
+    }
+
+    #[test]
+    fn test_attack_invalid() {
+        let mut rng = SeededRng::new(14);
+        let attack = Attack {
+            damage: Some(String::from("1d8 +")),
+            ..base_attack()
+        };
+        assert!(super::attack(&mut rng, &attack, RollLimits::default()).is_err());
+        let attack = Attack {
+            crit_range: Some(1),
+            ..base_attack()
+        };
+        assert!(super::attack(&mut rng, &attack, RollLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_save() {
+        let mut rng = SeededRng::new(14);
+        let fireball = Save {
+            modifier: 2,
+            dc: 15,
+            damage: Some(String::from("8d6")),
+            half_on_success: true,
+            advantage: false,
+            disadvantage: false,
+        };
+        for _ in 0..100 {
+            let result = super::save(&mut rng, &fireball, RollLimits::default()).unwrap();
+            assert_eq!(result.success, result.roll.total >= 15);
+            let rolled = result.damage.unwrap().total;
+            let expected = if result.success { rolled / 2 } else { rolled };
+            assert_eq!(result.damage_taken, Some(expected));
+        }
+
+        let hold_person = Save {
+            damage: None,
+            half_on_success: false,
+            ..fireball
+        };
+        let result = super::save(&mut rng, &hold_person, RollLimits::default()).unwrap();
+        assert!(result.damage_taken.is_none());
+    }
+}
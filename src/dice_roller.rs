@@ -1,10 +1,24 @@
+use crate::r2d2_rng::{RollSeed, SeededRng};
 use rand::Rng;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{error, fmt};
+use std::{cmp::Reverse, convert::TryFrom, error, fmt, ops::Range};
 
-// All the possible D&D dice
-const DICE_VALUES: [i32; 7] = [4, 6, 8, 10, 12, 20, 100];
+pub use expression::{
+    Advantage, Comparison, Condition, DiceTerm, Die, Explode, ExplodeKind, Expression, Face,
+    Operator, Reroll, Selection, SuccessPool,
+};
+
+mod expression;
+mod parser;
+
+/// Default for the most sides a die can have
+pub const DEFAULT_MAX_DIE: i32 = 1000;
+// Dice to suggest when asked for one that can't be rolled
+const STANDARD_DICE: [&str; 7] = ["d4", "d6", "d8", "d10", "d12", "d20", "d100"];
+// Most extra dice a single die can explode into
+pub(crate) const MAX_EXPLOSIONS: usize = 100;
+// Most times a single die can be rerolled
+pub(crate) const MAX_REROLLS: usize = 100;
 
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 /// Instructions for a roll
@@ -32,6 +46,21 @@ impl fmt::Display for RollInstruction {
     }
 }
 
+impl From<RollInstruction> for Expression {
+    fn from(instruction: RollInstruction) -> Self {
+        let dice = Self::Dice(DiceTerm::new(instruction.num, instruction.die));
+        match instruction.modifier {
+            0 => dice,
+            m if m > 0 => Self::binary(Operator::Add, dice, Self::Number(m)),
+            // i32::MIN has no positive counterpart, so it stays an addition
+            m => match m.checked_neg() {
+                Some(m) => Self::binary(Operator::Subtract, dice, Self::Number(m)),
+                None => Self::binary(Operator::Add, dice, Self::Number(m)),
+            },
+        }
+    }
+}
+
 impl From<RollInstruction> for String {
     #[must_use]
     fn from(instruction: RollInstruction) -> Self {
@@ -39,19 +68,242 @@ impl From<RollInstruction> for String {
     }
 }
 
-#[derive(Serialize, Debug)]
+/// What kind of problem stopped a roll
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollErrorKind {
+    /// The roll command couldn't be parsed
+    Syntax,
+    /// A die with no sides, too many sides or no faces
+    InvalidDie,
+    /// Too few or too many dice rolled or kept
+    DiceCount,
+    /// Rerolls or explosions that would never stop
+    Endless,
+    /// A total too big to fit in a number
+    Overflow,
+    DivideByZero,
+    /// Too many outcomes to work out the odds of
+    TooComplex,
+    /// Something that can be rolled, but not worked out
+    Unsupported,
+    /// A crit range that doesn't fit on a d20
+    InvalidCritRange,
+    /// Ability scores that the chosen method doesn't allow
+    InvalidAbilityScores,
+    /// A change to an encounter that doesn't fit its current state
+    InvalidEncounter,
+}
+
+impl RollErrorKind {
+    /// Stable identifier for the kind of error, for clients to match on
+    #[must_use]
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Syntax => "syntax_error",
+            Self::InvalidDie => "invalid_die",
+            Self::DiceCount => "dice_count",
+            Self::Endless => "endless_roll",
+            Self::Overflow => "overflow",
+            Self::DivideByZero => "divide_by_zero",
+            Self::TooComplex => "too_complex",
+            Self::Unsupported => "unsupported",
+            Self::InvalidCritRange => "invalid_crit_range",
+            Self::InvalidAbilityScores => "invalid_ability_scores",
+            Self::InvalidEncounter => "invalid_encounter",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct RollError {
+    pub kind: RollErrorKind,
     pub message: String,
+    /// Byte range of the roll command the error was found at, if it came from parsing
+    pub span: Option<Range<usize>>,
+    /// Things to try instead
+    pub suggestions: Vec<String>,
+}
+
+impl RollError {
+    #[must_use]
+    pub fn new(kind: RollErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            span: None,
+            suggestions: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    #[must_use]
+    pub fn with_suggestions(mut self, suggestions: &[&str]) -> Self {
+        self.suggestions = suggestions.iter().map(|s| (*s).to_string()).collect();
+        self
+    }
+
+    /// Stable identifier for the kind of error, for clients to match on
+    #[must_use]
+    pub const fn code(&self) -> &'static str {
+        self.kind.code()
+    }
 }
 
 impl fmt::Display for RollError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.message)
+        write!(f, "{}", self.message)?;
+        match self.suggestions.split_last() {
+            Some((last, [])) => write!(f, " Try again with something like {}.", last),
+            Some((last, rest)) => write!(
+                f,
+                " Try again with something like {} or {}.",
+                rest.join(", "),
+                last
+            ),
+            None => Ok(()),
+        }
     }
 }
 
 impl error::Error for RollError {}
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Lowest natural d20 roll that counts as a critical hit, such as 19 for a Champion
+pub struct CritRange(i32);
+
+impl CritRange {
+    /// # Errors
+    ///
+    /// Will return `RollError` if a natural 1 or less would crit, or a natural 20 wouldn't
+    pub fn new(lowest: i32) -> Result<Self, RollError> {
+        if (2..=20).contains(&lowest) {
+            Ok(Self(lowest))
+        } else {
+            Err(RollError::new(
+                RollErrorKind::InvalidCritRange,
+                "Crit ranges start somewhere from 2 to 20.",
+            )
+            .with_suggestions(&["20", "19"]))
+        }
+    }
+
+    #[must_use]
+    pub const fn lowest(self) -> i32 {
+        self.0
+    }
+}
+
+impl Default for CritRange {
+    fn default() -> Self {
+        Self(20)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Bounds on what a roll is allowed to ask for
+pub struct RollLimits {
+    /// Most sides a die, or faces a custom die, can have
+    pub max_die: i32,
+}
+
+impl Default for RollLimits {
+    fn default() -> Self {
+        Self {
+            max_die: DEFAULT_MAX_DIE,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A single die that was rolled
+pub struct DieRoll {
+    /// What the die adds to the total
+    pub value: i32,
+    /// Every face rolled for this die. More than one if explosions compounded onto it.
+    pub faces: Vec<i32>,
+    /// Faces that were rolled and then replaced by a reroll, in order
+    pub rerolled: Vec<i32>,
+    /// Name of the face rolled, for custom dice with named faces
+    pub label: Option<String>,
+    /// Whether the die counts towards the total, or was dropped by a keep or drop modifier
+    pub kept: bool,
+    /// Index of the die whose explosion caused this one to be rolled
+    pub exploded_from: Option<usize>,
+}
+
+impl DieRoll {
+    fn new(face: i32) -> Self {
+        Self {
+            value: face,
+            faces: vec![face],
+            rerolled: Vec::new(),
+            label: None,
+            kept: true,
+            exploded_from: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+/// Outcome of counting successes in a dice pool
+pub struct Successes {
+    /// Number of successes, including any that count twice
+    pub successes: i32,
+    /// Number of failures, which cancel out successes
+    pub failures: i32,
+    /// No successes and at least one failure
+    pub botch: bool,
+}
+
+impl Successes {
+    #[must_use]
+    pub const fn new(successes: i32, failures: i32) -> Self {
+        Self {
+            successes,
+            failures,
+            botch: successes == 0 && failures > 0,
+        }
+    }
+
+    /// Successes left once failures cancel them out
+    #[must_use]
+    pub const fn net(self) -> i32 {
+        self.successes - self.failures
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// Results of a single group of dice within a roll
+pub struct DiceRolls {
+    /// The dice that were rolled, such as `3d6`
+    pub dice: String,
+    /// Number of sides on the dice, if they are standard numbered dice
+    pub die: Option<i32>,
+    /// Whether the die was rolled with advantage or disadvantage
+    pub advantage: Option<Advantage>,
+    /// Every die rolled, including dropped ones
+    pub rolls: Vec<DieRoll>,
+    /// Success count if the dice are a pool, rather than added up
+    pub successes: Option<Successes>,
+    /// Sum of all kept dice in the group, or net successes for a pool
+    pub total: i32,
+}
+
+impl DiceRolls {
+    /// Every face rolled in the group, including dropped, rerolled and exploded dice
+    pub fn faces(&self) -> impl Iterator<Item = i32> + '_ {
+        self.rolls
+            .iter()
+            .flat_map(|r| r.rerolled.iter().chain(&r.faces).copied())
+    }
+}
+
 #[derive(Serialize, Debug)]
 /// Result of a roll
 pub struct RollResult {
@@ -59,83 +311,426 @@ pub struct RollResult {
     pub instruction: String,
     /// The results of all rolls made
     pub rolls: Vec<i32>,
+    /// The results of each group of dice in the instruction
+    pub terms: Vec<DiceRolls>,
+    /// Successes across every dice pool in the instruction, if there are any
+    pub successes: Option<Successes>,
     /// The total value of the entire roll
     pub total: i32,
+    /// Where in a random stream the roll came from, if it can be replayed
+    pub seed: Option<RollSeed>,
+    /// Whether a kept d20 landed in the crit range
+    pub critical: bool,
+    /// Whether a kept d20 landed on a natural 1
+    pub fumble: bool,
+}
+
+impl RollResult {
+    /// Flag critical hits and fumbles from the natural rolls of any kept d20s
+    pub fn set_crit_range(&mut self, crit_range: CritRange) {
+        let naturals: Vec<i32> = self
+            .terms
+            .iter()
+            .filter(|t| t.die == Some(20))
+            .flat_map(|t| &t.rolls)
+            .filter(|r| r.kept && r.exploded_from.is_none())
+            .filter_map(|r| r.faces.first().copied())
+            .collect();
+        self.critical = naturals.iter().any(|&n| n >= crit_range.0);
+        self.fumble = naturals.contains(&1);
+    }
 }
 
+/// Parse a roll command, such as `(2d6 + 3) * 2 - 1d4`, into an expression
+///
 /// # Errors
 ///
 /// Will return `RollError` if format is invalid
-pub fn parse_roll(cmd: &str) -> Result<RollInstruction, RollError> {
-    let re = Regex::new(r"(?P<num>\d+)d(?P<dice>\d+)(\s*\+\s*(?P<modifier>\d+))?").unwrap();
-    if re.is_match(cmd) {
-        Ok(re
-            .captures_iter(cmd)
-            .map(|cap| RollInstruction {
-                num: cap["num"].parse().unwrap(),
-                die: cap["dice"].parse().unwrap(),
-                modifier: match cap.name("modifier") {
-                    Some(m) => m.as_str().parse().unwrap(),
-                    None => 0,
-                },
-            })
-            .take(1)
-            .next()
-            .unwrap())
-    } else {
-        Err(RollError {
-            message: String::from("Invalid format. Try again with something like 1d20 or 3d6."),
-        })
-    }
+pub fn parse_roll(cmd: &str) -> Result<Expression, RollError> {
+    parser::parse(cmd)
 }
 
 fn gen_roll(rng: &mut impl Rng, die: i32) -> i32 {
     rng.gen_range(1..=die)
 }
 
-/// # Errors
-///
-/// Will return `RollError` if instruction is invalid
-pub fn roll(rng: &mut impl Rng, instruction: RollInstruction) -> Result<RollResult, RollError> {
-    // let mut rng = Pcg64::from_entropy();
-    let mut total = 0;
+pub(crate) fn overflow() -> RollError {
+    RollError::new(RollErrorKind::Overflow, "That roll is too big to add up!")
+}
+
+/// Make sure a die can be rolled
+fn validate_die(die: &Die, limits: RollLimits) -> Result<(), RollError> {
+    let message = match die {
+        Die::Sides(sides) if *sides < 1 => String::from("Dice need at least one side!"),
+        Die::Sides(sides) if *sides > limits.max_die => format!(
+            "Not a valid die. Dice can have up to {} sides.",
+            limits.max_die
+        ),
+        Die::Faces(faces) if faces.is_empty() => {
+            String::from("Custom dice need at least one face!")
+        }
+        Die::Faces(faces) if i32::try_from(faces.len()).map_or(true, |n| n > limits.max_die) => {
+            format!("Custom dice can have up to {} faces.", limits.max_die)
+        }
+        _ => return Ok(()),
+    };
+    Err(RollError::new(RollErrorKind::InvalidDie, message).with_suggestions(&STANDARD_DICE))
+}
+
+/// Make sure a group of dice can be rolled, and work out when they explode
+pub(crate) fn validate_dice(
+    dice: &DiceTerm,
+    limits: RollLimits,
+) -> Result<Option<(ExplodeKind, Condition)>, RollError> {
+    validate_die(&dice.die, limits)?;
+    let values = dice.die.values();
+    if let Some(Advantage::Advantage(_) | Advantage::Disadvantage) = dice.advantage {
+        if dice.num != 1 {
+            return Err(RollError::new(
+                RollErrorKind::DiceCount,
+                "Advantage is for a single die, such as 1d20adv",
+            )
+            .with_suggestions(&["1d20adv", "1d20dis"]));
+        }
+    }
+    if let Some(Advantage::Advantage(n)) = dice.advantage {
+        if n < 2 {
+            return Err(RollError::new(
+                RollErrorKind::DiceCount,
+                "Advantage needs at least two dice to pick from!",
+            ));
+        }
+    }
+    let (num, selection) = dice.rolled();
+    if dice.num < 1 {
+        return Err(RollError::new(
+            RollErrorKind::DiceCount,
+            "You have to roll something!",
+        ));
+    } else if num > 99 {
+        return Err(RollError::new(
+            RollErrorKind::DiceCount,
+            "Are you a god in this game?! Roll a more reasonable number of dice!",
+        ));
+    } else if let Some(Selection::KeepHighest(keep) | Selection::KeepLowest(keep)) = selection {
+        if keep < 1 {
+            return Err(RollError::new(
+                RollErrorKind::DiceCount,
+                "You have to keep at least one die!",
+            ));
+        }
+    }
+    if let Some(Reroll {
+        once: false,
+        condition,
+    }) = dice.reroll
+    {
+        if values.iter().all(|&v| condition.matches(v)) {
+            return Err(RollError::new(
+                RollErrorKind::Endless,
+                "Those dice would be rerolled forever!",
+            ));
+        }
+    }
+    match dice.explode {
+        Some(explode) => {
+            // Explode on the highest face unless told otherwise
+            let condition = explode.condition.unwrap_or_else(|| Condition {
+                comparison: Comparison::Equal,
+                value: values.iter().copied().max().unwrap_or_default(),
+            });
+            if values.iter().all(|&v| condition.matches(v)) {
+                return Err(RollError::new(
+                    RollErrorKind::Endless,
+                    "Those dice would explode forever!",
+                ));
+            }
+            Ok(Some((explode.kind, condition)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn roll_dice(
+    rng: &mut impl Rng,
+    dice: &DiceTerm,
+    limits: RollLimits,
+) -> Result<DiceRolls, RollError> {
+    let explode = validate_dice(dice, limits)?;
+    let (num, selection) = dice.rolled();
     let mut rolls = Vec::new();
-    if !DICE_VALUES.iter().any(|d| d == &instruction.die) {
-        return Err(RollError {
-            message: format!(
-                "Not a valid die. Try one of {}",
-                DICE_VALUES
-                    .iter()
-                    .map(|d| format!("d{}", d))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-        });
-    } else if instruction.num < 1 {
-        return Err(RollError {
-            message: String::from("You have to roll something!"),
-        });
-    } else if instruction.num > 99 {
-        return Err(RollError {
-            message: String::from(
-                "Are you a god in this game?! Roll a more reasonable number of dice!",
-            ),
-        });
+    for _ in 0..num {
+        rolls.push(roll_die(rng, dice));
+        if let Some((kind, condition)) = explode {
+            explode_die(rng, dice, kind, condition, &mut rolls)?;
+        }
     }
-    for _ in 0..instruction.num {
-        let roll = gen_roll(rng, instruction.die);
-        total += roll;
-        rolls.push(roll);
+    if let Some(selection) = selection {
+        select(&mut rolls, selection);
     }
-    total += instruction.modifier;
+    let successes = dice.pool.map(|pool| count_successes(&rolls, pool));
+    let total = match successes {
+        Some(successes) => successes.net(),
+        // Custom faces can be as big as an i32, so even two of them can overflow
+        None => rolls
+            .iter()
+            .filter(|r| r.kept)
+            .try_fold(0_i32, |total, r| total.checked_add(r.value))
+            .ok_or_else(overflow)?,
+    };
 
-    Ok(RollResult {
-        instruction: instruction.into(),
-        rolls,
+    Ok(DiceRolls {
+        dice: dice.to_string(),
+        die: dice.die.sides(),
+        advantage: dice.advantage,
         total,
+        successes,
+        rolls,
     })
 }
 
+/// Count the kept dice that meet the pool's success and failure targets
+fn count_successes(rolls: &[DieRoll], pool: SuccessPool) -> Successes {
+    let mut successes = 0;
+    let mut failures = 0;
+    for roll in rolls.iter().filter(|r| r.kept) {
+        if pool.success.matches(roll.value) {
+            successes += 1;
+            if pool.double.is_some_and(|d| d.matches(roll.value)) {
+                successes += 1;
+            }
+        }
+        if pool.failure.is_some_and(|f| f.matches(roll.value)) {
+            failures += 1;
+        }
+    }
+    Successes::new(successes, failures)
+}
+
+/// Roll any kind of die once
+fn gen_die(rng: &mut impl Rng, die: &Die) -> DieRoll {
+    match die {
+        Die::Sides(sides) => DieRoll::new(gen_roll(rng, *sides)),
+        Die::Fudge => DieRoll::new(rng.gen_range(-1..=1)),
+        Die::Faces(faces) => {
+            let face = &faces[rng.gen_range(0..faces.len())];
+            let mut roll = DieRoll::new(face.value());
+            if let Face::Named(name) = face {
+                roll.label = Some(name.clone());
+            }
+            roll
+        }
+    }
+}
+
+/// Roll a single die, rerolling it while it meets the reroll condition
+fn roll_die(rng: &mut impl Rng, dice: &DiceTerm) -> DieRoll {
+    let mut roll = gen_die(rng, &dice.die);
+    if let Some(reroll) = dice.reroll {
+        let limit = if reroll.once { 1 } else { MAX_REROLLS };
+        for _ in 0..limit {
+            if !reroll.condition.matches(roll.value) {
+                break;
+            }
+            let mut next = gen_die(rng, &dice.die);
+            next.rerolled = std::mem::take(&mut roll.rerolled);
+            next.rerolled.push(roll.value);
+            roll = next;
+        }
+    }
+    roll
+}
+
+/// Keep rolling extra dice while the last die rolled meets the explode condition
+fn explode_die(
+    rng: &mut impl Rng,
+    dice: &DiceTerm,
+    kind: ExplodeKind,
+    condition: Condition,
+    rolls: &mut Vec<DieRoll>,
+) -> Result<(), RollError> {
+    let mut trigger = rolls.len() - 1;
+    let mut face = rolls[trigger].value;
+    for _ in 0..MAX_EXPLOSIONS {
+        if !condition.matches(face) {
+            break;
+        }
+        let mut extra = roll_die(rng, dice);
+        face = extra.value;
+        match kind {
+            ExplodeKind::Compound => {
+                let compound = &mut rolls[trigger];
+                compound.value = compound.value.checked_add(face).ok_or_else(overflow)?;
+                compound.faces.append(&mut extra.faces);
+                compound.rerolled.append(&mut extra.rerolled);
+            }
+            ExplodeKind::Explode | ExplodeKind::Penetrate => {
+                if kind == ExplodeKind::Penetrate {
+                    extra.value = extra.value.checked_sub(1).ok_or_else(overflow)?;
+                }
+                extra.exploded_from = Some(trigger);
+                rolls.push(extra);
+                trigger = rolls.len() - 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mark which dice count towards the total
+fn select(rolls: &mut [DieRoll], selection: Selection) {
+    let (count, highest_first, keep) = match selection {
+        Selection::KeepHighest(n) => (n, true, true),
+        Selection::KeepLowest(n) => (n, false, true),
+        Selection::DropHighest(n) => (n, true, false),
+        Selection::DropLowest(n) => (n, false, false),
+    };
+    let count = usize::try_from(count).unwrap_or(0);
+
+    // Stable sorts, so ties are settled by the order the dice were rolled
+    let mut order: Vec<usize> = (0..rolls.len()).collect();
+    if highest_first {
+        order.sort_by_key(|&i| Reverse(rolls[i].value));
+    } else {
+        order.sort_by_key(|&i| rolls[i].value);
+    }
+    for (rank, i) in order.into_iter().enumerate() {
+        rolls[i].kept = (rank < count) == keep;
+    }
+}
+
+/// Integer division that rounds down, as the rules do
+pub(crate) fn div_floor(lhs: i32, rhs: i32) -> Result<i32, RollError> {
+    if rhs == 0 {
+        return Err(RollError::new(
+            RollErrorKind::DivideByZero,
+            "You can't divide by zero!",
+        ));
+    }
+    let quotient = lhs.checked_div(rhs).ok_or_else(overflow)?;
+    if lhs % rhs != 0 && (lhs < 0) != (rhs < 0) {
+        Ok(quotient - 1)
+    } else {
+        Ok(quotient)
+    }
+}
+
+/// Walk the expression tree, rolling any dice along the way
+fn evaluate(
+    rng: &mut impl Rng,
+    expr: &Expression,
+    limits: RollLimits,
+    terms: &mut Vec<DiceRolls>,
+) -> Result<i32, RollError> {
+    match expr {
+        Expression::Number(n) => Ok(*n),
+        Expression::Dice(dice) => {
+            let rolls = roll_dice(rng, dice, limits)?;
+            let total = rolls.total;
+            terms.push(rolls);
+            Ok(total)
+        }
+        Expression::Negate(inner) => evaluate(rng, inner, limits, terms)?
+            .checked_neg()
+            .ok_or_else(overflow),
+        Expression::Binary(op, lhs, rhs) => {
+            let lhs = evaluate(rng, lhs, limits, terms)?;
+            let rhs = evaluate(rng, rhs, limits, terms)?;
+            match op {
+                Operator::Add => lhs.checked_add(rhs).ok_or_else(overflow),
+                Operator::Subtract => lhs.checked_sub(rhs).ok_or_else(overflow),
+                Operator::Multiply => lhs.checked_mul(rhs).ok_or_else(overflow),
+                Operator::Divide => div_floor(lhs, rhs),
+            }
+        }
+    }
+}
+
+/// Roll every die in an expression and total it up
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression contains invalid dice
+pub fn roll_expression(rng: &mut impl Rng, expr: &Expression) -> Result<RollResult, RollError> {
+    roll_with_limits(rng, expr, RollLimits::default())
+}
+
+/// Roll every die in an expression, with custom bounds on the dice allowed
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression contains invalid dice
+pub fn roll_with_limits(
+    rng: &mut impl Rng,
+    expr: &Expression,
+    limits: RollLimits,
+) -> Result<RollResult, RollError> {
+    let mut terms = Vec::new();
+    let total = evaluate(rng, expr, limits, &mut terms)?;
+    let successes =
+        terms
+            .iter()
+            .filter_map(|t| t.successes)
+            .try_fold(None, |sum: Option<Successes>, b| {
+                Ok(Some(match sum {
+                    Some(a) => Successes::new(
+                        a.successes.checked_add(b.successes).ok_or_else(overflow)?,
+                        a.failures.checked_add(b.failures).ok_or_else(overflow)?,
+                    ),
+                    None => b,
+                }))
+            })?;
+
+    let mut result = RollResult {
+        instruction: expr.to_string(),
+        rolls: terms.iter().flat_map(DiceRolls::faces).collect(),
+        terms,
+        successes,
+        total,
+        seed: None,
+        critical: false,
+        fumble: false,
+    };
+    result.set_crit_range(CritRange::default());
+    Ok(result)
+}
+
+/// Roll an expression, recording where in the stream the roll started so it can be replayed
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression can't be rolled
+pub fn roll_seeded(
+    rng: &mut SeededRng,
+    expr: &Expression,
+    limits: RollLimits,
+) -> Result<RollResult, RollError> {
+    let seed = rng.roll_seed();
+    let mut result = roll_with_limits(rng, expr, limits)?;
+    result.seed = Some(seed);
+    Ok(result)
+}
+
+/// Roll an expression again from the same seed and position, giving the same result
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression can't be rolled
+pub fn replay(
+    expr: &Expression,
+    limits: RollLimits,
+    seed: RollSeed,
+) -> Result<RollResult, RollError> {
+    roll_seeded(&mut SeededRng::resume(seed), expr, limits)
+}
+
+/// # Errors
+///
+/// Will return `RollError` if instruction is invalid
+pub fn roll(rng: &mut impl Rng, instruction: RollInstruction) -> Result<RollResult, RollError> {
+    roll_expression(rng, &instruction.into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -148,11 +743,11 @@ mod tests {
         let roll = parse_roll("1d8").unwrap();
         assert_eq!(
             roll,
-            RollInstruction {
+            Expression::from(RollInstruction {
                 num: 1,
                 die: 8,
                 modifier: 0
-            }
+            })
         );
     }
 
@@ -161,11 +756,11 @@ mod tests {
         let roll = parse_roll("3d6").unwrap();
         assert_eq!(
             roll,
-            RollInstruction {
+            Expression::from(RollInstruction {
                 num: 3,
                 die: 6,
                 modifier: 0
-            }
+            })
         );
     }
 
@@ -174,11 +769,11 @@ mod tests {
         let roll = parse_roll("1d8 + 3").unwrap();
         assert_eq!(
             roll,
-            RollInstruction {
+            Expression::from(RollInstruction {
                 num: 1,
                 die: 8,
                 modifier: 3
-            }
+            })
         );
     }
 
@@ -193,20 +788,40 @@ mod tests {
         assert_eq!(roll1, roll4);
     }
 
+    #[test]
+    fn test_parse_roll_negative_modifier() {
+        let roll = parse_roll("1d8 - 3").unwrap();
+        assert_eq!(
+            roll,
+            Expression::from(RollInstruction {
+                num: 1,
+                die: 8,
+                modifier: -3
+            })
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_parse_roll_fail() {
         parse_roll("3e6").unwrap();
     }
 
+    #[test]
+    #[should_panic]
+    fn test_parse_roll_trailing_input() {
+        parse_roll("1d20+5 2d6").unwrap();
+    }
+
     #[test]
     fn test_gen_roll() {
-        let mut rng = rand::thread_rng();
+        // Seeded, so the check can't fail by chance
+        let mut rng = Pcg64::seed_from_u64(6);
 
-        for d in &DICE_VALUES {
+        for d in &[2, 3, 4, 6, 8, 10, 12, 20, 30, 100] {
             let mut occurrences: HashMap<i32, i32> = HashMap::new();
-            // Try and get a sample that will have an occurrence for every value
-            for _ in 0..d * d {
+            // Plenty of samples for every value to have an occurrence
+            for _ in 0..100 * d {
                 let roll = gen_roll(&mut rng, *d);
                 let count = occurrences.entry(roll).or_insert(0);
                 *count += 1;
@@ -311,4 +926,479 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_roll_instruction_display_matches_expression() {
+        let instruction = RollInstruction {
+            num: 2,
+            die: 6,
+            modifier: -1,
+        };
+        assert_eq!(
+            instruction.to_string(),
+            Expression::from(instruction).to_string()
+        );
+    }
+
+    #[test]
+    fn test_roll_expression_terms() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("(2d6 + 3) * 2 - 1d4").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        assert_eq!(roll.instruction, "(2d6 + 3) * 2 - 1d4");
+        assert_eq!(roll.terms.len(), 2);
+        assert_eq!(roll.terms[0].dice, "2d6");
+        assert_eq!(roll.terms[0].rolls.len(), 2);
+        assert!(roll.terms[0].rolls.iter().all(|r| r.kept));
+        assert_eq!(roll.terms[1].dice, "1d4");
+        assert_eq!(roll.rolls.len(), 3);
+        assert_eq!(
+            roll.total,
+            (roll.terms[0].total + 3) * 2 - roll.terms[1].total
+        );
+    }
+
+    #[test]
+    fn test_roll_expression_division_rounds_down() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("7 / 2 + -7 / 2").unwrap();
+        assert_eq!(roll_expression(&mut rng, &expr).unwrap().total, -1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_roll_expression_divide_by_zero() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("1d6 / 0").unwrap();
+        roll_expression(&mut rng, &expr).unwrap();
+    }
+
+    fn kept(rolls: &[DieRoll]) -> Vec<bool> {
+        rolls.iter().map(|r| r.kept).collect()
+    }
+
+    fn die_rolls(values: &[i32]) -> Vec<DieRoll> {
+        values.iter().map(|&value| DieRoll::new(value)).collect()
+    }
+
+    #[test]
+    fn test_select_keep_highest() {
+        let mut rolls = die_rolls(&[3, 6, 1, 4]);
+        select(&mut rolls, Selection::KeepHighest(3));
+        assert_eq!(kept(&rolls), vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn test_select_keep_lowest() {
+        let mut rolls = die_rolls(&[17, 4]);
+        select(&mut rolls, Selection::KeepLowest(1));
+        assert_eq!(kept(&rolls), vec![false, true]);
+    }
+
+    #[test]
+    fn test_select_drop_highest() {
+        let mut rolls = die_rolls(&[2, 5, 5]);
+        select(&mut rolls, Selection::DropHighest(1));
+        assert_eq!(kept(&rolls), vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_select_drop_lowest_ties() {
+        let mut rolls = die_rolls(&[1, 6, 1, 3]);
+        select(&mut rolls, Selection::DropLowest(1));
+        assert_eq!(kept(&rolls), vec![false, true, true, true]);
+    }
+
+    #[test]
+    fn test_select_more_than_rolled() {
+        let mut rolls = die_rolls(&[2, 5]);
+        select(&mut rolls, Selection::KeepHighest(3));
+        assert_eq!(kept(&rolls), vec![true, true]);
+        select(&mut rolls, Selection::DropLowest(3));
+        assert_eq!(kept(&rolls), vec![false, false]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_select_keep_none() {
+        let mut rng = Pcg64::from_entropy();
+        roll_expression(&mut rng, &parse_roll("2d6kh0").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_roll_keep_highest_total() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("4d6kh3").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        assert_eq!(term.rolls.len(), 4);
+        assert_eq!(term.rolls.iter().filter(|r| r.kept).count(), 3);
+        let lowest = term.rolls.iter().map(|r| r.value).min().unwrap();
+        let sum: i32 = term.rolls.iter().map(|r| r.value).sum();
+        assert_eq!(roll.total, sum - lowest);
+    }
+
+    #[test]
+    fn test_roll_explode_links_extra_dice() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let expr = parse_roll("20d4!").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        assert!(term.rolls.len() > 20);
+        for (i, die) in term.rolls.iter().enumerate() {
+            if let Some(trigger) = die.exploded_from {
+                assert!(trigger < i);
+                assert_eq!(term.rolls[trigger].value, 4);
+            }
+        }
+        assert_eq!(
+            term.rolls
+                .iter()
+                .filter(|r| r.exploded_from.is_none())
+                .count(),
+            20
+        );
+        assert_eq!(roll.total, term.rolls.iter().map(|r| r.value).sum::<i32>());
+    }
+
+    #[test]
+    fn test_roll_compound() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let expr = parse_roll("20d4!!").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        assert_eq!(term.rolls.len(), 20);
+        assert!(term.rolls.iter().any(|r| r.faces.len() > 1));
+        for die in &term.rolls {
+            assert_eq!(die.value, die.faces.iter().sum::<i32>());
+        }
+    }
+
+    #[test]
+    fn test_roll_penetrate() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let expr = parse_roll("20d4!p").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        assert!(term.rolls.len() > 20);
+        for die in &term.rolls {
+            let penalty = i32::from(die.exploded_from.is_some());
+            assert_eq!(die.value, die.faces[0] - penalty);
+        }
+    }
+
+    #[test]
+    fn test_explode_chain_capped() {
+        let mut rng = Pcg64::from_entropy();
+        // Explode on anything but a 1 on a d100, which could take a long time
+        let mut rolls = vec![DieRoll::new(100)];
+        explode_die(
+            &mut rng,
+            &DiceTerm::new(1, 100),
+            ExplodeKind::Explode,
+            Condition {
+                comparison: Comparison::Greater,
+                value: 1,
+            },
+            &mut rolls,
+        )
+        .unwrap();
+        assert!(rolls.len() <= MAX_EXPLOSIONS + 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_roll_explode_forever() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("1d6!>0").unwrap();
+        roll_expression(&mut rng, &expr).unwrap();
+    }
+
+    #[test]
+    fn test_roll_reroll_once() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let expr = parse_roll("50d6r<3").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        assert!(term.rolls.iter().any(|r| !r.rerolled.is_empty()));
+        for die in &term.rolls {
+            assert!(die.rerolled.len() <= 1);
+            assert!(die.rerolled.iter().all(|&face| face < 3));
+        }
+        assert_eq!(
+            term.faces().count(),
+            50 + term.rolls.iter().map(|r| r.rerolled.len()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_roll_reroll_until() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let expr = parse_roll("50d6rr<3").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let term = &roll.terms[0];
+        for die in &term.rolls {
+            assert!(die.value >= 3);
+            assert!(die.rerolled.iter().all(|&face| face < 3));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_roll_reroll_forever() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("1d6rr<7").unwrap();
+        roll_expression(&mut rng, &expr).unwrap();
+    }
+
+    #[test]
+    fn test_count_successes() {
+        let pool = parse_roll("1d10>=7f1t10").unwrap();
+        let Expression::Dice(DiceTerm {
+            pool: Some(pool), ..
+        }) = pool
+        else {
+            unreachable!()
+        };
+        let successes = count_successes(&die_rolls(&[1, 3, 7, 9, 10]), pool);
+        assert_eq!(successes, Successes::new(4, 1));
+        assert_eq!(successes.net(), 3);
+        assert!(!successes.botch);
+    }
+
+    #[test]
+    fn test_count_successes_botch() {
+        let pool = SuccessPool {
+            success: Condition {
+                comparison: Comparison::Greater,
+                value: 4,
+            },
+            failure: Some(Condition {
+                comparison: Comparison::Equal,
+                value: 1,
+            }),
+            double: None,
+        };
+        let successes = count_successes(&die_rolls(&[1, 2, 4]), pool);
+        assert_eq!(successes.net(), -1);
+        assert!(successes.botch);
+    }
+
+    #[test]
+    fn test_roll_success_pool_total() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("8d10>=7 + 6d6>4f1").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        let successes = roll.successes.unwrap();
+        assert!(successes.successes <= 14);
+        assert_eq!(roll.total, successes.net());
+        assert!(roll.terms.iter().all(|t| t.successes.is_some()));
+    }
+
+    #[test]
+    fn test_roll_without_pool_has_no_successes() {
+        let mut rng = Pcg64::from_entropy();
+        let roll = roll_expression(&mut rng, &parse_roll("2d6").unwrap()).unwrap();
+        assert!(roll.successes.is_none());
+        assert!(roll.terms[0].successes.is_none());
+    }
+
+    #[test]
+    fn test_roll_any_die_size() {
+        let mut rng = Pcg64::from_entropy();
+        for die in &[1, 2, 3, 30, 1000] {
+            let roll = roll(
+                &mut rng,
+                RollInstruction {
+                    num: 1,
+                    die: *die,
+                    modifier: 0,
+                },
+            )
+            .unwrap();
+            assert!(roll.total >= 1);
+            assert!(roll.total <= *die);
+            assert_eq!(roll.terms[0].die, Some(*die));
+        }
+    }
+
+    #[test]
+    fn test_roll_limits() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("1d1001").unwrap();
+        assert!(roll_expression(&mut rng, &expr).is_err());
+        let limits = RollLimits { max_die: 2000 };
+        assert!(roll_with_limits(&mut rng, &expr, limits).is_ok());
+        let limits = RollLimits { max_die: 4 };
+        let expr = parse_roll("1d{1,2,3,4,5}").unwrap();
+        assert!(roll_with_limits(&mut rng, &expr, limits).is_err());
+    }
+
+    #[test]
+    fn test_roll_advantage() {
+        let mut rng = Pcg64::from_entropy();
+        for _ in 0..20 {
+            let roll = roll_expression(&mut rng, &parse_roll("1d20adv + 2").unwrap()).unwrap();
+            let term = &roll.terms[0];
+            assert_eq!(term.advantage, Some(Advantage::Advantage(2)));
+            assert_eq!(term.rolls.len(), 2);
+            let best = term.rolls.iter().map(|r| r.value).max().unwrap();
+            assert_eq!(roll.total, best + 2);
+            assert_eq!(term.rolls.iter().filter(|r| r.kept).count(), 1);
+
+            let roll = roll_expression(&mut rng, &parse_roll("1d20dis").unwrap()).unwrap();
+            let worst = roll.terms[0].rolls.iter().map(|r| r.value).min().unwrap();
+            assert_eq!(roll.total, worst);
+
+            let roll = roll_expression(&mut rng, &parse_roll("1d20adv3").unwrap()).unwrap();
+            assert_eq!(roll.terms[0].rolls.len(), 3);
+
+            let roll = roll_expression(&mut rng, &parse_roll("1d20advdis").unwrap()).unwrap();
+            assert_eq!(roll.terms[0].rolls.len(), 1);
+            assert!(roll.terms[0].rolls[0].kept);
+        }
+    }
+
+    #[test]
+    fn test_roll_advantage_invalid() {
+        let mut rng = Pcg64::from_entropy();
+        for cmd in &[
+            "2d20adv",
+            "1d20adv1",
+            "1d20adv100",
+            // Too many dice to count without overflowing
+            "1d20adv99999999",
+            "99d20adv99999999",
+            "2000000000d20dis",
+        ] {
+            let err = roll_expression(&mut rng, &parse_roll(cmd).unwrap()).unwrap_err();
+            assert_eq!(err.kind, RollErrorKind::DiceCount);
+        }
+    }
+
+    #[test]
+    fn test_roll_crits() {
+        let mut rng = Pcg64::seed_from_u64(13);
+        let expr = parse_roll("1d20 + 5").unwrap();
+        let mut seen = (false, false);
+        for _ in 0..200 {
+            let mut roll = roll_expression(&mut rng, &expr).unwrap();
+            let natural = roll.rolls[0];
+            assert_eq!(roll.critical, natural == 20);
+            assert_eq!(roll.fumble, natural == 1);
+            roll.set_crit_range(CritRange::new(19).unwrap());
+            assert_eq!(roll.critical, natural >= 19);
+            seen = (seen.0 || roll.critical, seen.1 || roll.fumble);
+        }
+        assert_eq!(seen, (true, true));
+        // Only the kept die counts with advantage
+        let roll = roll_expression(&mut rng, &parse_roll("1d20dis").unwrap()).unwrap();
+        assert_eq!(roll.critical, roll.total == 20);
+        // Damage dice don't crit
+        let roll = roll_expression(&mut rng, &parse_roll("1d1").unwrap()).unwrap();
+        assert!(!roll.fumble);
+    }
+
+    #[test]
+    fn test_crit_range() {
+        assert_eq!(CritRange::default().lowest(), 20);
+        assert_eq!(CritRange::new(18).unwrap().lowest(), 18);
+        assert_eq!(
+            CritRange::new(1).unwrap_err().kind,
+            RollErrorKind::InvalidCritRange
+        );
+        assert!(CritRange::new(21).is_err());
+    }
+
+    #[test]
+    fn test_roll_error_kinds() {
+        let mut rng = Pcg64::from_entropy();
+        let kind = |rng: &mut Pcg64, cmd| {
+            roll_expression(rng, &parse_roll(cmd).unwrap())
+                .unwrap_err()
+                .kind
+        };
+        assert_eq!(kind(&mut rng, "1d1001"), RollErrorKind::InvalidDie);
+        assert_eq!(kind(&mut rng, "100d6"), RollErrorKind::DiceCount);
+        assert_eq!(kind(&mut rng, "1d6rr<7"), RollErrorKind::Endless);
+        assert_eq!(kind(&mut rng, "1d6 / 0"), RollErrorKind::DivideByZero);
+        let err = roll_expression(&mut rng, &parse_roll("1d0").unwrap()).unwrap_err();
+        assert_eq!(err.code(), "invalid_die");
+        assert!(err.span.is_none());
+        assert!(err.suggestions.contains(&String::from("d20")));
+    }
+
+    #[test]
+    fn test_roll_fudge() {
+        let mut rng = Pcg64::from_entropy();
+        let roll = roll_expression(&mut rng, &parse_roll("20dF").unwrap()).unwrap();
+        let term = &roll.terms[0];
+        assert_eq!(term.die, None);
+        assert!(term.rolls.iter().all(|r| (-1..=1).contains(&r.value)));
+        assert!(roll.total >= -20);
+        assert!(roll.total <= 20);
+    }
+
+    #[test]
+    fn test_roll_custom_faces() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("10d{1,1,2,3,5,8}").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        assert!(roll.terms[0]
+            .rolls
+            .iter()
+            .all(|r| [1, 2, 3, 5, 8].contains(&r.value) && r.label.is_none()));
+    }
+
+    #[test]
+    fn test_roll_custom_faces_overflow() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("2d{2147483647}").unwrap();
+        let err = roll_expression(&mut rng, &expr).unwrap_err();
+        assert_eq!(err.kind, RollErrorKind::Overflow);
+    }
+
+    #[test]
+    fn test_roll_named_faces() {
+        let mut rng = Pcg64::from_entropy();
+        let expr = parse_roll("3d{hit,miss,crit}").unwrap();
+        let roll = roll_expression(&mut rng, &expr).unwrap();
+        assert_eq!(roll.total, 0);
+        for die in &roll.terms[0].rolls {
+            let label = die.label.as_deref().unwrap();
+            assert!(["hit", "miss", "crit"].contains(&label));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_roll_fudge_explode_forever() {
+        let mut rng = Pcg64::from_entropy();
+        roll_expression(&mut rng, &parse_roll("4dF!>-2").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_roll_seeded() {
+        let mut rng = SeededRng::new(42);
+        let expr = parse_roll("4d6dl1 + 2d8!").unwrap();
+        let first = roll_seeded(&mut rng, &expr, RollLimits::default()).unwrap();
+        let second = roll_seeded(&mut rng, &expr, RollLimits::default()).unwrap();
+        assert_eq!(
+            first.seed,
+            Some(RollSeed {
+                seed: 42,
+                position: 0
+            })
+        );
+        let seed = second.seed.unwrap();
+        assert_eq!(seed.seed, 42);
+        assert!(seed.position > 0);
+
+        let replayed = replay(&expr, RollLimits::default(), seed).unwrap();
+        assert_eq!(replayed.rolls, second.rolls);
+        assert_eq!(replayed.total, second.total);
+        assert_eq!(replayed.seed, second.seed);
+        let replayed = replay(&expr, RollLimits::default(), first.seed.unwrap()).unwrap();
+        assert_eq!(replayed.rolls, first.rolls);
+    }
 }
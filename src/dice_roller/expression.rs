@@ -0,0 +1,577 @@
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Arithmetic operators that combine parts of a roll
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Operator {
+    /// Binding strength of the operator. Higher binds tighter.
+    const fn precedence(self) -> u8 {
+        match self {
+            Self::Add | Self::Subtract => 1,
+            Self::Multiply | Self::Divide => 2,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Which dice in a group count towards the total, such as the `kh3` in `4d6kh3`
+pub enum Selection {
+    /// Keep this many of the highest dice
+    KeepHighest(i32),
+    /// Keep this many of the lowest dice
+    KeepLowest(i32),
+    /// Drop this many of the highest dice
+    DropHighest(i32),
+    /// Drop this many of the lowest dice
+    DropLowest(i32),
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeepHighest(n) => write!(f, "kh{}", n),
+            Self::KeepLowest(n) => write!(f, "kl{}", n),
+            Self::DropHighest(n) => write!(f, "dh{}", n),
+            Self::DropLowest(n) => write!(f, "dl{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+/// Rolling a die more than once and taking the best or worst, such as the `adv` in `1d20adv`
+pub enum Advantage {
+    /// Keep the highest of this many dice. Two normally, or three with Elven Accuracy.
+    Advantage(i32),
+    /// Keep the lowest of two dice
+    Disadvantage,
+    /// Advantage and disadvantage together cancel out, however many sources of each
+    Cancelled,
+}
+
+impl fmt::Display for Advantage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Advantage(2) => write!(f, "adv"),
+            Self::Advantage(n) => write!(f, "adv{}", n),
+            Self::Disadvantage => write!(f, "dis"),
+            Self::Cancelled => write!(f, "advdis"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Ways to compare a die against a target number
+pub enum Comparison {
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// A check against the face of a single die, such as the `>8` in `1d10!>8`
+pub struct Condition {
+    pub comparison: Comparison,
+    pub value: i32,
+}
+
+impl Condition {
+    /// Whether a die showing this face meets the condition
+    #[must_use]
+    pub const fn matches(self, face: i32) -> bool {
+        match self.comparison {
+            Comparison::Equal => face == self.value,
+            Comparison::Greater => face > self.value,
+            Comparison::GreaterEqual => face >= self.value,
+            Comparison::Less => face < self.value,
+            Comparison::LessEqual => face <= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self.comparison {
+            Comparison::Equal => "",
+            Comparison::Greater => ">",
+            Comparison::GreaterEqual => ">=",
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+        };
+        write!(f, "{}{}", symbol, self.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// How extra dice from an explosion are counted
+pub enum ExplodeKind {
+    /// Each extra die is added as a separate die, `!`
+    Explode,
+    /// Extra dice are added onto the die that exploded, `!!`
+    Compound,
+    /// Each extra die is added separately with one subtracted from it, `!p`
+    Penetrate,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Roll another die whenever a die meets the condition, such as `3d6!`
+pub struct Explode {
+    pub kind: ExplodeKind,
+    /// When to explode. Defaults to the highest face of the die.
+    pub condition: Option<Condition>,
+}
+
+impl fmt::Display for Explode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self.kind {
+            ExplodeKind::Explode => "!",
+            ExplodeKind::Compound => "!!",
+            ExplodeKind::Penetrate => "!p",
+        };
+        write!(f, "{}", symbol)?;
+        if let Some(condition) = self.condition {
+            write!(f, "{}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Roll a die again when it meets the condition, such as `2d6r<3`
+pub struct Reroll {
+    /// Only reroll a die once, `r` or `ro`, rather than until it stops meeting the condition, `rr`
+    pub once: bool,
+    pub condition: Condition,
+}
+
+impl fmt::Display for Reroll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            if self.once { "ro" } else { "rr" },
+            self.condition
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Count dice that meet a target instead of adding them up, such as `6d6>4f1`
+pub struct SuccessPool {
+    /// Dice that count as a success
+    pub success: Condition,
+    /// Dice that count as a failure and cancel out a success, `f`
+    pub failure: Option<Condition>,
+    /// Successful dice that count twice, `t`
+    pub double: Option<Condition>,
+}
+
+impl fmt::Display for SuccessPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.success.comparison == Comparison::Equal {
+            write!(f, "={}", self.success.value)?;
+        } else {
+            write!(f, "{}", self.success)?;
+        }
+        if let Some(failure) = self.failure {
+            write!(f, "f{}", failure)?;
+        }
+        if let Some(double) = self.double {
+            write!(f, "t{}", double)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// One face of a custom die
+pub enum Face {
+    /// A face showing a number, which is added to the total
+    Number(i32),
+    /// A face showing a word, such as `hit`. Counts as zero towards the total.
+    Named(String),
+}
+
+impl Face {
+    /// What the face adds to the total
+    #[must_use]
+    pub const fn value(&self) -> i32 {
+        match self {
+            Self::Number(n) => *n,
+            Self::Named(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for Face {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// The kind of die to roll
+pub enum Die {
+    /// A die numbered from one up to its number of sides, such as `d20`
+    Sides(i32),
+    /// A Fudge/Fate die with two each of -1, 0 and +1, `dF`
+    Fudge,
+    /// A die with a custom list of faces, such as `d{1,1,2,3,5,8}`
+    Faces(Vec<Face>),
+}
+
+impl Die {
+    /// Every value the die can add to the total
+    #[must_use]
+    pub fn values(&self) -> Vec<i32> {
+        match self {
+            Self::Sides(sides) => (1..=*sides).collect(),
+            Self::Fudge => vec![-1, 0, 1],
+            Self::Faces(faces) => faces.iter().map(Face::value).collect(),
+        }
+    }
+
+    /// Number of sides, if this is a standard numbered die
+    #[must_use]
+    pub const fn sides(&self) -> Option<i32> {
+        match self {
+            Self::Sides(sides) => Some(*sides),
+            Self::Fudge | Self::Faces(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Die {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sides(sides) => write!(f, "{}", sides),
+            Self::Fudge => write!(f, "F"),
+            Self::Faces(faces) => write!(
+                f,
+                "{{{}}}",
+                faces
+                    .iter()
+                    .map(Face::to_string)
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// A group of identical dice, such as `3d6`
+pub struct DiceTerm {
+    /// Number of dice to roll
+    pub num: i32,
+    /// The kind of die to roll
+    pub die: Die,
+    /// Whether to reroll any of the dice
+    pub reroll: Option<Reroll>,
+    /// Whether the dice explode
+    pub explode: Option<Explode>,
+    /// Whether the die is rolled with advantage or disadvantage
+    pub advantage: Option<Advantage>,
+    /// Which of the dice to keep
+    pub selection: Option<Selection>,
+    /// Count successes rather than adding up the dice
+    pub pool: Option<SuccessPool>,
+}
+
+impl DiceTerm {
+    /// Plain numbered dice with no modifiers
+    #[must_use]
+    pub const fn new(num: i32, sides: i32) -> Self {
+        Self {
+            num,
+            die: Die::Sides(sides),
+            reroll: None,
+            explode: None,
+            advantage: None,
+            selection: None,
+            pool: None,
+        }
+    }
+
+    /// Number of dice actually rolled, and which of them are kept, once advantage is applied.
+    /// Saturates rather than overflowing, so huge counts are still caught as too many dice.
+    #[must_use]
+    pub const fn rolled(&self) -> (i32, Option<Selection>) {
+        match self.advantage {
+            Some(Advantage::Advantage(n)) => {
+                (self.num.saturating_mul(n), Some(Selection::KeepHighest(1)))
+            }
+            Some(Advantage::Disadvantage) => {
+                (self.num.saturating_mul(2), Some(Selection::KeepLowest(1)))
+            }
+            Some(Advantage::Cancelled) | None => (self.num, self.selection),
+        }
+    }
+}
+
+impl fmt::Display for DiceTerm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.num, self.die)?;
+        if let Some(reroll) = self.reroll {
+            write!(f, "{}", reroll)?;
+        }
+        if let Some(explode) = self.explode {
+            write!(f, "{}", explode)?;
+        }
+        if let Some(advantage) = self.advantage {
+            write!(f, "{}", advantage)?;
+        }
+        if let Some(selection) = self.selection {
+            write!(f, "{}", selection)?;
+        }
+        if let Some(pool) = self.pool {
+            write!(f, "{}", pool)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+/// Expression tree for a roll, such as `(2d6 + 3) * 2 - 1d4`
+pub enum Expression {
+    /// A flat number
+    Number(i32),
+    /// Dice to roll and sum
+    Dice(DiceTerm),
+    /// Unary minus
+    Negate(Box<Self>),
+    /// Two expressions combined with an operator
+    Binary(Operator, Box<Self>, Box<Self>),
+}
+
+impl Expression {
+    /// Combine two expressions with an operator
+    #[must_use]
+    pub fn binary(op: Operator, lhs: Self, rhs: Self) -> Self {
+        Self::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    /// Damage for a critical hit: every die is rolled twice as many times, but flat numbers
+    /// stay the same, so `2d6 + 3` becomes `4d6 + 3`
+    #[must_use]
+    pub fn crit_damage(&self) -> Self {
+        match self {
+            Self::Number(n) => Self::Number(*n),
+            Self::Dice(dice) => Self::Dice(DiceTerm {
+                num: dice.num.saturating_mul(2),
+                ..dice.clone()
+            }),
+            Self::Negate(inner) => Self::Negate(Box::new(inner.crit_damage())),
+            Self::Binary(op, lhs, rhs) => Self::binary(*op, lhs.crit_damage(), rhs.crit_damage()),
+        }
+    }
+
+    /// Binding strength of the outermost node, used to decide on parentheses
+    const fn precedence(&self) -> u8 {
+        match self {
+            Self::Binary(op, _, _) => op.precedence(),
+            Self::Number(_) | Self::Dice(_) | Self::Negate(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Dice(dice) => write!(f, "{}", dice),
+            Self::Negate(inner) => {
+                if inner.precedence() < 3 {
+                    write!(f, "-({})", inner)
+                } else {
+                    write!(f, "-{}", inner)
+                }
+            }
+            Self::Binary(op, lhs, rhs) => {
+                // Operators are left-associative, so the right-hand side also
+                // needs parentheses when it binds exactly as tightly.
+                if lhs.precedence() < op.precedence() {
+                    write!(f, "({})", lhs)?;
+                } else {
+                    write!(f, "{}", lhs)?;
+                }
+                write!(f, " {} ", op)?;
+                if rhs.precedence() <= op.precedence() {
+                    write!(f, "({})", rhs)
+                } else {
+                    write!(f, "{}", rhs)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_precedence() {
+        let expr = Expression::binary(
+            Operator::Subtract,
+            Expression::binary(
+                Operator::Multiply,
+                Expression::binary(
+                    Operator::Add,
+                    Expression::Dice(DiceTerm::new(2, 6)),
+                    Expression::Number(3),
+                ),
+                Expression::Number(2),
+            ),
+            Expression::Dice(DiceTerm::new(1, 4)),
+        );
+        assert_eq!(expr.to_string(), "(2d6 + 3) * 2 - 1d4");
+    }
+
+    #[test]
+    fn test_display_right_associative_grouping() {
+        let expr = Expression::binary(
+            Operator::Subtract,
+            Expression::Number(10),
+            Expression::binary(
+                Operator::Subtract,
+                Expression::Number(4),
+                Expression::Number(3),
+            ),
+        );
+        assert_eq!(expr.to_string(), "10 - (4 - 3)");
+    }
+
+    #[test]
+    fn test_crit_damage() {
+        let expr = Expression::binary(
+            Operator::Add,
+            Expression::Dice(DiceTerm::new(2, 6)),
+            Expression::binary(
+                Operator::Multiply,
+                Expression::Number(3),
+                Expression::Negate(Box::new(Expression::Dice(DiceTerm::new(1, 4)))),
+            ),
+        );
+        assert_eq!(expr.crit_damage().to_string(), "4d6 + 3 * -2d4");
+    }
+
+    #[test]
+    fn test_display_advantage() {
+        let mut dice = DiceTerm {
+            advantage: Some(Advantage::Advantage(2)),
+            ..DiceTerm::new(1, 20)
+        };
+        assert_eq!(dice.to_string(), "1d20adv");
+        dice.advantage = Some(Advantage::Advantage(3));
+        assert_eq!(dice.to_string(), "1d20adv3");
+        dice.advantage = Some(Advantage::Disadvantage);
+        assert_eq!(dice.to_string(), "1d20dis");
+        dice.advantage = Some(Advantage::Cancelled);
+        assert_eq!(dice.to_string(), "1d20advdis");
+    }
+
+    #[test]
+    fn test_display_selection() {
+        let dice = DiceTerm {
+            selection: Some(Selection::KeepHighest(3)),
+            ..DiceTerm::new(4, 6)
+        };
+        assert_eq!(dice.to_string(), "4d6kh3");
+    }
+
+    #[test]
+    fn test_display_explode() {
+        let dice = DiceTerm {
+            explode: Some(Explode {
+                kind: ExplodeKind::Penetrate,
+                condition: Some(Condition {
+                    comparison: Comparison::GreaterEqual,
+                    value: 5,
+                }),
+            }),
+            selection: Some(Selection::DropLowest(1)),
+            ..DiceTerm::new(4, 6)
+        };
+        assert_eq!(dice.to_string(), "4d6!p>=5dl1");
+    }
+
+    #[test]
+    fn test_display_reroll() {
+        let dice = DiceTerm {
+            reroll: Some(Reroll {
+                once: true,
+                condition: Condition {
+                    comparison: Comparison::Equal,
+                    value: 1,
+                },
+            }),
+            ..DiceTerm::new(1, 20)
+        };
+        assert_eq!(dice.to_string(), "1d20ro1");
+    }
+
+    #[test]
+    fn test_display_success_pool() {
+        let dice = DiceTerm {
+            pool: Some(SuccessPool {
+                success: Condition {
+                    comparison: Comparison::GreaterEqual,
+                    value: 7,
+                },
+                failure: Some(Condition {
+                    comparison: Comparison::Equal,
+                    value: 1,
+                }),
+                double: Some(Condition {
+                    comparison: Comparison::Equal,
+                    value: 10,
+                }),
+            }),
+            ..DiceTerm::new(8, 10)
+        };
+        assert_eq!(dice.to_string(), "8d10>=7f1t10");
+    }
+
+    #[test]
+    fn test_display_custom_dice() {
+        let fudge = DiceTerm {
+            die: Die::Fudge,
+            ..DiceTerm::new(4, 0)
+        };
+        assert_eq!(fudge.to_string(), "4dF");
+        let faces = DiceTerm {
+            die: Die::Faces(vec![
+                Face::Named(String::from("hit")),
+                Face::Number(-1),
+                Face::Number(2),
+            ]),
+            ..DiceTerm::new(1, 0)
+        };
+        assert_eq!(faces.to_string(), "1d{hit,-1,2}");
+    }
+}
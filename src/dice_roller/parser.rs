@@ -0,0 +1,958 @@
+use super::{
+    expression::{
+        Advantage, Comparison, Condition, DiceTerm, Die, Explode, ExplodeKind, Expression, Face,
+        Operator, Reroll, Selection, SuccessPool,
+    },
+    RollError, RollErrorKind,
+};
+use std::ops::Range;
+
+const EXAMPLES: [&str; 2] = ["1d20", "3d6"];
+const MODIFIERS: [&str; 10] = ["kh", "kl", "dh", "dl", "r", "ro", "rr", "!", "!!", "!p"];
+
+/// Words the grammar understands. Modifiers can be written back to back,
+/// such as `!pkh1`, so letters are split on the longest known word.
+/// Names of custom faces, between braces, are left whole.
+const KEYWORDS: [&str; 15] = [
+    "adv", "d", "dh", "dis", "dl", "f", "F", "k", "kh", "kl", "p", "r", "ro", "rr", "t",
+];
+
+// Rolls are parsed and walked recursively, so both are capped to keep the stack in check.
+// The length also bounds chains like `1+1+1`, which nest one level per operator.
+const MAX_LENGTH: usize = 1000;
+// Parentheses and signs, such as `((-(1d6)))`
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum TokenKind {
+    Number(i32),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Bang,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    /// Byte range of the token in the input
+    span: Range<usize>,
+}
+
+fn invalid(message: &str, span: Range<usize>) -> RollError {
+    RollError::new(
+        RollErrorKind::Syntax,
+        format!("{} at position {}.", message, span.start),
+    )
+    .with_span(span)
+    .with_suggestions(&EXAMPLES)
+}
+
+/// Split a roll command into tokens
+fn tokenize(input: &str) -> Result<Vec<Token>, RollError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    let mut in_braces = false;
+
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            '{' => {
+                in_braces = true;
+                TokenKind::LBrace
+            }
+            '}' => {
+                in_braces = false;
+                TokenKind::RBrace
+            }
+            ',' => TokenKind::Comma,
+            '!' => TokenKind::Bang,
+            '=' => TokenKind::Equal,
+            '>' if chars.next_if(|(_, c)| *c == '=').is_some() => TokenKind::GreaterEqual,
+            '>' => TokenKind::Greater,
+            '<' if chars.next_if(|(_, c)| *c == '=').is_some() => TokenKind::LessEqual,
+            '<' => TokenKind::Less,
+            c if c.is_ascii_digit() => {
+                while chars.next_if(|(_, d)| d.is_ascii_digit()).is_some() {}
+                let end = chars.peek().map_or(input.len(), |(i, _)| *i);
+                let number = input[start..end]
+                    .parse()
+                    .map_err(|_| invalid("Number is too large", start..end))?;
+                TokenKind::Number(number)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let keyword = KEYWORDS
+                    .iter()
+                    .filter(|k| !in_braces && input[start..].starts_with(*k))
+                    .max_by_key(|k| k.len());
+                if let Some(keyword) = keyword {
+                    for _ in 1..keyword.len() {
+                        chars.next();
+                    }
+                    TokenKind::Ident((*keyword).to_string())
+                } else {
+                    while chars.next_if(|(_, l)| l.is_ascii_alphabetic()).is_some() {}
+                    let end = chars.peek().map_or(input.len(), |(i, _)| *i);
+                    TokenKind::Ident(input[start..end].to_string())
+                }
+            }
+            c => {
+                return Err(invalid(
+                    &format!("Unexpected `{}`", c),
+                    start..start + c.len_utf8(),
+                ))
+            }
+        };
+        let end = chars.peek().map_or(input.len(), |(i, _)| *i);
+        tokens.push(Token {
+            kind,
+            span: start..end,
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser over a list of tokens.
+///
+/// ```text
+/// expression := term (("+" | "-") term)*
+/// term       := unary (("*" | "/") unary)*
+/// unary      := ("-" | "+") unary | atom
+/// atom       := dice | NUMBER | "(" expression ")"
+/// dice       := NUMBER? "d" die modifier*
+/// die        := NUMBER | "F" | "{" face ("," face)* "}"
+/// face       := "-"? NUMBER | NAME
+/// modifier   := reroll | explode | advantage | select | pool
+/// reroll     := ("r" | "ro" | "rr") condition?
+/// explode    := "!" ("!" | "p")? condition?
+/// advantage  := "adv" NUMBER? | "dis"
+/// select     := ("kh" | "kl" | "dh" | "dl") NUMBER?
+/// pool       := condition (("f" | "t") condition)*
+/// condition  := ("=" | ">" | ">=" | "<" | "<=")? NUMBER
+/// ```
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    input_len: usize,
+    /// Parentheses and signs currently open
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    /// Span of the current token, or the end of the input
+    fn span(&self) -> Range<usize> {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.input_len..self.input_len, |t| t.span.clone())
+    }
+
+    fn advance(&mut self) -> Option<TokenKind> {
+        let token = self.tokens.get(self.pos).map(|t| t.kind.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn unexpected(&self) -> RollError {
+        match self.tokens.get(self.pos) {
+            Some(_) => invalid("Unexpected input", self.span()),
+            None => invalid("Unexpected end of roll", self.span()),
+        }
+    }
+
+    /// Go a level deeper into the expression, as long as it isn't nested too far
+    fn nest(&mut self) -> Result<(), RollError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(invalid("Too many nested parentheses or signs", self.span()));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<Expression, RollError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Plus) => Operator::Add,
+                Some(TokenKind::Minus) => Operator::Subtract,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.term()?;
+            lhs = Expression::binary(op, lhs, rhs);
+        }
+    }
+
+    fn term(&mut self) -> Result<Expression, RollError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Star) => Operator::Multiply,
+                Some(TokenKind::Slash) => Operator::Divide,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.unary()?;
+            lhs = Expression::binary(op, lhs, rhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, RollError> {
+        match self.peek() {
+            Some(TokenKind::Minus) => {
+                self.advance();
+                self.nest()?;
+                let inner = self.unary()?;
+                self.depth -= 1;
+                Ok(Expression::Negate(Box::new(inner)))
+            }
+            Some(TokenKind::Plus) => {
+                self.advance();
+                self.nest()?;
+                let inner = self.unary()?;
+                self.depth -= 1;
+                Ok(inner)
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expression, RollError> {
+        match self.peek() {
+            Some(TokenKind::Number(num)) => {
+                let num = *num;
+                self.advance();
+                if self.peek_die() {
+                    self.dice(num)
+                } else {
+                    Ok(Expression::Number(num))
+                }
+            }
+            Some(TokenKind::Ident(_)) if self.peek_die() => self.dice(1),
+            Some(TokenKind::LParen) => {
+                self.advance();
+                self.nest()?;
+                let expr = self.expression()?;
+                self.depth -= 1;
+                match self.peek() {
+                    Some(TokenKind::RParen) => {
+                        self.advance();
+                        Ok(expr)
+                    }
+                    _ => Err(invalid("Missing closing parenthesis", self.span())),
+                }
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn peek_die(&self) -> bool {
+        matches!(self.peek(), Some(TokenKind::Ident(i)) if i == "d")
+    }
+
+    fn dice(&mut self, num: i32) -> Result<Expression, RollError> {
+        // Consume the `d`
+        self.advance();
+        let mut dice = DiceTerm {
+            die: self.die()?,
+            ..DiceTerm::new(num, 0)
+        };
+        loop {
+            let span = self.span();
+            match self.peek() {
+                Some(TokenKind::Bang) => {
+                    if dice.explode.is_some() {
+                        return Err(invalid("Dice can only explode once", span));
+                    }
+                    self.advance();
+                    dice.explode = Some(self.explode()?);
+                }
+                Some(TokenKind::Ident(modifier)) if modifier.starts_with('r') => {
+                    if dice.reroll.is_some() {
+                        return Err(invalid("Only one reroll is allowed", span));
+                    }
+                    let once = modifier != "rr";
+                    self.advance();
+                    // Rerolling ones is by far the most common
+                    let condition = self.condition()?.unwrap_or(Condition {
+                        comparison: Comparison::Equal,
+                        value: 1,
+                    });
+                    dice.reroll = Some(Reroll { once, condition });
+                }
+                Some(
+                    TokenKind::Equal
+                    | TokenKind::Greater
+                    | TokenKind::GreaterEqual
+                    | TokenKind::Less
+                    | TokenKind::LessEqual,
+                ) => {
+                    if dice.pool.is_some() {
+                        return Err(invalid("Only one success target is allowed", span));
+                    }
+                    dice.pool = self.condition()?.map(|success| SuccessPool {
+                        success,
+                        failure: None,
+                        double: None,
+                    });
+                }
+                Some(TokenKind::Ident(modifier)) if modifier == "f" || modifier == "t" => {
+                    let failure = modifier == "f";
+                    let pool = dice.pool.as_mut().ok_or_else(|| {
+                        invalid("Set a success target, such as >=7, first", span.clone())
+                            .with_suggestions(&["3d10>=7f1"])
+                    })?;
+                    let slot = if failure {
+                        &mut pool.failure
+                    } else {
+                        &mut pool.double
+                    };
+                    if slot.is_some() {
+                        return Err(invalid("Only one of each pool modifier is allowed", span));
+                    }
+                    self.advance();
+                    *slot = Some(self.condition()?.ok_or_else(|| {
+                        invalid("Missing number to compare against", self.span())
+                    })?);
+                }
+                Some(TokenKind::Ident(modifier)) if modifier == "adv" || modifier == "dis" => {
+                    let advantage = modifier == "adv";
+                    if dice.selection.is_some() {
+                        return Err(invalid(
+                            "Advantage can't be combined with keep or drop",
+                            span,
+                        ));
+                    }
+                    self.advance();
+                    dice.advantage = Some(self.advantage(dice.advantage, advantage, span)?);
+                }
+                Some(TokenKind::Ident(modifier)) => {
+                    let selection: fn(i32) -> Selection = match modifier.as_str() {
+                        "kh" | "k" => Selection::KeepHighest,
+                        "kl" => Selection::KeepLowest,
+                        "dh" => Selection::DropHighest,
+                        "dl" => Selection::DropLowest,
+                        _ => {
+                            return Err(
+                                invalid("Unknown dice modifier", span).with_suggestions(&MODIFIERS)
+                            )
+                        }
+                    };
+                    if dice.selection.is_some() {
+                        return Err(invalid("Only one keep or drop is allowed", span));
+                    } else if dice.advantage.is_some() {
+                        return Err(invalid(
+                            "Advantage can't be combined with keep or drop",
+                            span,
+                        ));
+                    }
+                    self.advance();
+                    dice.selection = Some(selection(self.count(1)));
+                }
+                _ => return Ok(Expression::Dice(dice)),
+            }
+        }
+    }
+
+    fn die(&mut self) -> Result<Die, RollError> {
+        match self.peek() {
+            Some(TokenKind::Ident(i)) if i == "F" => {
+                self.advance();
+                Ok(Die::Fudge)
+            }
+            Some(TokenKind::LBrace) => {
+                self.advance();
+                let mut faces = vec![self.face()?];
+                loop {
+                    match self.advance() {
+                        Some(TokenKind::Comma) => faces.push(self.face()?),
+                        Some(TokenKind::RBrace) => return Ok(Die::Faces(faces)),
+                        _ => {
+                            self.pos -= 1;
+                            return Err(invalid("Missing closing brace", self.span()));
+                        }
+                    }
+                }
+            }
+            _ => Ok(Die::Sides(self.number("Missing die size")?)),
+        }
+    }
+
+    fn face(&mut self) -> Result<Face, RollError> {
+        match self.peek() {
+            Some(TokenKind::Minus) => {
+                self.advance();
+                Ok(Face::Number(-self.number("Missing face")?))
+            }
+            Some(TokenKind::Ident(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok(Face::Named(name))
+            }
+            _ => Ok(Face::Number(self.number("Missing face")?)),
+        }
+    }
+
+    /// A number that has to be there
+    fn number(&mut self, missing: &str) -> Result<i32, RollError> {
+        if let Some(TokenKind::Number(n)) = self.peek() {
+            let n = *n;
+            self.advance();
+            Ok(n)
+        } else {
+            Err(invalid(missing, self.span()))
+        }
+    }
+
+    /// Add advantage, or disadvantage, to what a die already has.
+    /// Having both cancels out, no matter how many dice the advantage had.
+    fn advantage(
+        &mut self,
+        current: Option<Advantage>,
+        advantage: bool,
+        span: Range<usize>,
+    ) -> Result<Advantage, RollError> {
+        match (current, advantage) {
+            (None, true) => Ok(Advantage::Advantage(self.count(2))),
+            (None, false) => Ok(Advantage::Disadvantage),
+            (Some(Advantage::Disadvantage), true) => {
+                self.count(2);
+                Ok(Advantage::Cancelled)
+            }
+            (Some(Advantage::Advantage(_)), false) => Ok(Advantage::Cancelled),
+            _ => Err(invalid(
+                "Only one advantage and one disadvantage are allowed",
+                span,
+            )),
+        }
+    }
+
+    /// Kind and condition of an explosion following a `!`
+    fn explode(&mut self) -> Result<Explode, RollError> {
+        let kind = match self.peek() {
+            Some(TokenKind::Bang) => ExplodeKind::Compound,
+            Some(TokenKind::Ident(i)) if i == "p" => ExplodeKind::Penetrate,
+            _ => ExplodeKind::Explode,
+        };
+        if kind != ExplodeKind::Explode {
+            self.advance();
+        }
+        Ok(Explode {
+            kind,
+            condition: self.condition()?,
+        })
+    }
+
+    /// Optional comparison against a die face. A bare number means equal to.
+    fn condition(&mut self) -> Result<Option<Condition>, RollError> {
+        let comparison = match self.peek() {
+            Some(TokenKind::Equal) => Comparison::Equal,
+            Some(TokenKind::Greater) => Comparison::Greater,
+            Some(TokenKind::GreaterEqual) => Comparison::GreaterEqual,
+            Some(TokenKind::Less) => Comparison::Less,
+            Some(TokenKind::LessEqual) => Comparison::LessEqual,
+            Some(TokenKind::Number(_)) => {
+                return Ok(Some(Condition {
+                    comparison: Comparison::Equal,
+                    value: self.number("Missing number to compare against")?,
+                }))
+            }
+            _ => return Ok(None),
+        };
+        self.advance();
+        Ok(Some(Condition {
+            comparison,
+            value: self.number("Missing number to compare against")?,
+        }))
+    }
+
+    /// Optional count after a modifier
+    fn count(&mut self, default: i32) -> i32 {
+        if let Some(TokenKind::Number(n)) = self.peek() {
+            let n = *n;
+            self.advance();
+            n
+        } else {
+            default
+        }
+    }
+}
+
+/// Parse a roll command into an expression tree
+///
+/// # Errors
+///
+/// Will return `RollError` if the command is not a valid roll expression
+pub fn parse(input: &str) -> Result<Expression, RollError> {
+    if input.len() > MAX_LENGTH {
+        return Err(RollError::new(
+            RollErrorKind::Syntax,
+            format!("Rolls can be up to {} characters long.", MAX_LENGTH),
+        )
+        .with_suggestions(&EXAMPLES));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        input_len: input.len(),
+        depth: 0,
+    };
+    let expr = parser.expression()?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected());
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dice(num: i32, die: i32) -> Expression {
+        Expression::Dice(DiceTerm::new(num, die))
+    }
+
+    fn explode(num: i32, die: i32, kind: ExplodeKind, condition: Option<Condition>) -> Expression {
+        Expression::Dice(DiceTerm {
+            explode: Some(Explode { kind, condition }),
+            ..DiceTerm::new(num, die)
+        })
+    }
+
+    fn select(num: i32, die: i32, selection: Selection) -> Expression {
+        Expression::Dice(DiceTerm {
+            selection: Some(selection),
+            ..DiceTerm::new(num, die)
+        })
+    }
+
+    #[test]
+    fn test_tokenize_spans() {
+        let tokens = tokenize("12d6 +3").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token {
+                    kind: TokenKind::Number(12),
+                    span: 0..2
+                },
+                Token {
+                    kind: TokenKind::Ident(String::from("d")),
+                    span: 2..3
+                },
+                Token {
+                    kind: TokenKind::Number(6),
+                    span: 3..4
+                },
+                Token {
+                    kind: TokenKind::Plus,
+                    span: 5..6
+                },
+                Token {
+                    kind: TokenKind::Number(3),
+                    span: 6..7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        assert_eq!(
+            parse("1d4 + 2 * 3").unwrap(),
+            Expression::binary(
+                Operator::Add,
+                dice(1, 4),
+                Expression::binary(
+                    Operator::Multiply,
+                    Expression::Number(2),
+                    Expression::Number(3)
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_parentheses() {
+        assert_eq!(
+            parse("(2d6+3)*2 - 1d4").unwrap(),
+            Expression::binary(
+                Operator::Subtract,
+                Expression::binary(
+                    Operator::Multiply,
+                    Expression::binary(Operator::Add, dice(2, 6), Expression::Number(3)),
+                    Expression::Number(2)
+                ),
+                dice(1, 4)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_implicit_single_die() {
+        assert_eq!(parse("d20").unwrap(), dice(1, 20));
+    }
+
+    #[test]
+    fn test_parse_unary_minus() {
+        assert_eq!(
+            parse("-1d4").unwrap(),
+            Expression::Negate(Box::new(dice(1, 4)))
+        );
+    }
+
+    #[test]
+    fn test_parse_trailing_input() {
+        assert!(parse("1d20+5 2d6").is_err());
+    }
+
+    #[test]
+    fn test_parse_unclosed_parenthesis() {
+        assert!(parse("(1d20 + 5").is_err());
+    }
+
+    #[test]
+    fn test_parse_too_deep() {
+        let nested = format!("{}1d6{}", "(".repeat(5000), ")".repeat(5000));
+        assert!(parse(&nested).is_err());
+        assert!(parse(&"-".repeat(5000)).is_err());
+        assert!(parse(&format!("{}1", "+-".repeat(40))).is_err());
+        assert!(parse(&format!("1{}", "+1".repeat(5000))).is_err());
+        let limit = format!("{}1d6{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert!(parse(&limit).is_ok());
+        let deeper = format!("({})", limit);
+        assert_eq!(
+            parse(&deeper).map_err(|e| e.kind),
+            Err(RollErrorKind::Syntax)
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_die() {
+        assert!(parse("2d").is_err());
+        assert!(parse("2d + 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert!(parse("").is_err());
+        assert!(parse("   ").is_err());
+    }
+
+    #[test]
+    fn test_parse_number_too_large() {
+        assert!(parse("99999999999d6").is_err());
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(
+            parse("4d6kh3").unwrap(),
+            select(4, 6, Selection::KeepHighest(3))
+        );
+        assert_eq!(
+            parse("2d20kl1").unwrap(),
+            select(2, 20, Selection::KeepLowest(1))
+        );
+        assert_eq!(
+            parse("4d6dl1").unwrap(),
+            select(4, 6, Selection::DropLowest(1))
+        );
+        assert_eq!(
+            parse("3d8dh").unwrap(),
+            select(3, 8, Selection::DropHighest(1))
+        );
+        assert_eq!(
+            parse("2d20k").unwrap(),
+            select(2, 20, Selection::KeepHighest(1))
+        );
+    }
+
+    #[test]
+    fn test_parse_selection_in_expression() {
+        assert_eq!(
+            parse("2d20kh1 + 5").unwrap(),
+            Expression::binary(
+                Operator::Add,
+                select(2, 20, Selection::KeepHighest(1)),
+                Expression::Number(5)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_selection_invalid() {
+        assert!(parse("4d6kh3kl1").is_err());
+        assert!(parse("4d6xy").is_err());
+    }
+
+    #[test]
+    fn test_parse_advantage() {
+        let dice = |advantage| {
+            Expression::Dice(DiceTerm {
+                advantage: Some(advantage),
+                ..DiceTerm::new(1, 20)
+            })
+        };
+        assert_eq!(parse("1d20adv").unwrap(), dice(Advantage::Advantage(2)));
+        assert_eq!(parse("d20 adv3").unwrap(), dice(Advantage::Advantage(3)));
+        assert_eq!(parse("1d20dis").unwrap(), dice(Advantage::Disadvantage));
+        assert_eq!(parse("1d20adv3dis").unwrap(), dice(Advantage::Cancelled));
+        assert_eq!(parse("1d20disadv").unwrap(), dice(Advantage::Cancelled));
+        assert_eq!(parse("1d20adv + 5").unwrap().to_string(), "1d20adv + 5");
+    }
+
+    #[test]
+    fn test_parse_advantage_invalid() {
+        assert!(parse("1d20advadv").is_err());
+        assert!(parse("1d20advdisadv").is_err());
+        assert!(parse("1d20advkh1").is_err());
+        assert!(parse("2d20kh1dis").is_err());
+    }
+
+    #[test]
+    fn test_parse_error_details() {
+        let err = parse("2d6 + 4d6xy").unwrap_err();
+        assert_eq!(err.kind, RollErrorKind::Syntax);
+        assert_eq!(err.code(), "syntax_error");
+        assert_eq!(err.span, Some(9..11));
+        assert!(err.suggestions.contains(&String::from("kh")));
+
+        let err = parse("1d20 +").unwrap_err();
+        assert_eq!(err.span, Some(6..6));
+        assert_eq!(
+            err.to_string(),
+            "Unexpected end of roll at position 6. Try again with something like 1d20 or 3d6."
+        );
+        assert_eq!(parse("1d20 $ 2").unwrap_err().span, Some(5..6));
+    }
+
+    #[test]
+    fn test_tokenize_keywords() {
+        let kinds: Vec<TokenKind> = tokenize("4d6!pkh1")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Number(4),
+                TokenKind::Ident(String::from("d")),
+                TokenKind::Number(6),
+                TokenKind::Bang,
+                TokenKind::Ident(String::from("p")),
+                TokenKind::Ident(String::from("kh")),
+                TokenKind::Number(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_explode() {
+        assert_eq!(
+            parse("3d6!").unwrap(),
+            explode(3, 6, ExplodeKind::Explode, None)
+        );
+        assert_eq!(
+            parse("1d10!>8").unwrap(),
+            explode(
+                1,
+                10,
+                ExplodeKind::Explode,
+                Some(Condition {
+                    comparison: Comparison::Greater,
+                    value: 8
+                })
+            )
+        );
+        assert_eq!(
+            parse("5d6!!").unwrap(),
+            explode(5, 6, ExplodeKind::Compound, None)
+        );
+        assert_eq!(
+            parse("2d6!p").unwrap(),
+            explode(2, 6, ExplodeKind::Penetrate, None)
+        );
+        assert_eq!(
+            parse("2d6!5").unwrap(),
+            explode(
+                2,
+                6,
+                ExplodeKind::Explode,
+                Some(Condition {
+                    comparison: Comparison::Equal,
+                    value: 5
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_explode_and_select() {
+        assert_eq!(
+            parse("4d6!pkh3").unwrap(),
+            Expression::Dice(DiceTerm {
+                explode: Some(Explode {
+                    kind: ExplodeKind::Penetrate,
+                    condition: None
+                }),
+                selection: Some(Selection::KeepHighest(3)),
+                ..DiceTerm::new(4, 6)
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_explode_invalid() {
+        assert!(parse("3d6!!!").is_err());
+        assert!(parse("3d6!>").is_err());
+    }
+
+    #[test]
+    fn test_parse_reroll() {
+        let reroll = |once, comparison, value| {
+            Expression::Dice(DiceTerm {
+                reroll: Some(Reroll {
+                    once,
+                    condition: Condition { comparison, value },
+                }),
+                ..DiceTerm::new(2, 6)
+            })
+        };
+        assert_eq!(parse("2d6r<3").unwrap(), reroll(true, Comparison::Less, 3));
+        assert_eq!(parse("2d6ro1").unwrap(), reroll(true, Comparison::Equal, 1));
+        assert_eq!(parse("2d6rr").unwrap(), reroll(false, Comparison::Equal, 1));
+        assert_eq!(
+            parse("2d6rr<=2").unwrap(),
+            reroll(false, Comparison::LessEqual, 2)
+        );
+    }
+
+    #[test]
+    fn test_parse_reroll_twice() {
+        assert!(parse("2d6r1r2").is_err());
+    }
+
+    #[test]
+    fn test_parse_success_pool() {
+        let pool = |success, failure, double| {
+            Expression::Dice(DiceTerm {
+                pool: Some(SuccessPool {
+                    success,
+                    failure,
+                    double,
+                }),
+                ..DiceTerm::new(8, 10)
+            })
+        };
+        let condition = |comparison, value| Condition { comparison, value };
+        assert_eq!(
+            parse("8d10>=7").unwrap(),
+            pool(condition(Comparison::GreaterEqual, 7), None, None)
+        );
+        assert_eq!(
+            parse("8d10>4f1").unwrap(),
+            pool(
+                condition(Comparison::Greater, 4),
+                Some(condition(Comparison::Equal, 1)),
+                None
+            )
+        );
+        assert_eq!(
+            parse("8d10>=7t10f<2").unwrap(),
+            pool(
+                condition(Comparison::GreaterEqual, 7),
+                Some(condition(Comparison::Less, 2)),
+                Some(condition(Comparison::Equal, 10))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_success_pool_invalid() {
+        assert!(parse("6d6f1").is_err());
+        assert!(parse("6d6>4f1f2").is_err());
+        assert!(parse("6d6>4>5").is_err());
+        assert!(parse("6d6>4t").is_err());
+    }
+
+    #[test]
+    fn test_parse_any_die_size() {
+        assert_eq!(parse("1d3").unwrap(), dice(1, 3));
+        assert_eq!(parse("2d30").unwrap(), dice(2, 30));
+    }
+
+    #[test]
+    fn test_parse_fudge() {
+        assert_eq!(
+            parse("4dF+1").unwrap(),
+            Expression::binary(
+                Operator::Add,
+                Expression::Dice(DiceTerm {
+                    die: Die::Fudge,
+                    ..DiceTerm::new(4, 0)
+                }),
+                Expression::Number(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_custom_faces() {
+        assert_eq!(
+            parse("1d{1, 1, 2, 3, 5, 8}").unwrap(),
+            Expression::Dice(DiceTerm {
+                die: Die::Faces(
+                    [1, 1, 2, 3, 5, 8]
+                        .iter()
+                        .map(|&n| Face::Number(n))
+                        .collect()
+                ),
+                ..DiceTerm::new(1, 0)
+            })
+        );
+        assert_eq!(
+            parse("2d{hit,miss,dodge,-1}kh1").unwrap(),
+            Expression::Dice(DiceTerm {
+                die: Die::Faces(vec![
+                    Face::Named(String::from("hit")),
+                    Face::Named(String::from("miss")),
+                    Face::Named(String::from("dodge")),
+                    Face::Number(-1),
+                ]),
+                selection: Some(Selection::KeepHighest(1)),
+                ..DiceTerm::new(2, 0)
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_custom_faces_invalid() {
+        assert!(parse("1d{}").is_err());
+        assert!(parse("1d{1,2").is_err());
+        assert!(parse("1d{1,,2}").is_err());
+    }
+}
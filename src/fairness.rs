@@ -0,0 +1,437 @@
+//! Checks on whether the dice being rolled are fair, from the stats and roll history
+//!
+//! Each die gets a chi-square goodness-of-fit test and a Kolmogorov–Smirnov test against
+//! an even spread over its faces. Where there is recent history for the die, a runs test
+//! checks that high and low rolls don't clump together.
+// Roll counts are nowhere near big enough to lose precision as floats
+#![allow(clippy::cast_precision_loss)]
+use crate::{
+    dice_roller::{self, Die, Expression},
+    models::{RollHistory, RollStat},
+    schema::{roll_history, roll_stats},
+};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    f64::consts::PI,
+    hash::BuildHasher,
+};
+
+/// Dice with a p-value below this look biased
+pub const SIGNIFICANCE: f64 = 0.01;
+// Split between the three tests (Bonferroni), so fair dice are flagged no more often overall
+const TEST_SIGNIFICANCE: f64 = SIGNIFICANCE / 3.0;
+// Chi-square is only reliable once every face is expected this many times
+const MIN_EXPECTED: f64 = 5.0;
+// Rolls from the history used for the runs test
+const RECENT_ROLLS: i64 = 1000;
+// Iterations before giving up on a series converging
+const MAX_ITERATIONS: usize = 500;
+const EPSILON: f64 = 1e-14;
+
+/// Natural log of the gamma function, with the Lanczos approximation
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // Reflection, for small values
+        return (PI / (PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, c) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+    let t = x + 7.5;
+    0.5f64.mul_add((2.0 * PI).ln(), (x + 0.5) * t.ln()) - t + sum.ln()
+}
+
+/// Upper regularized incomplete gamma function, `Q(a, x)`
+#[allow(clippy::many_single_char_names)]
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let prefix = a.mul_add(x.ln(), -x) - ln_gamma(a);
+    if x < a + 1.0 {
+        // Series for P(a, x), which converges quickly here
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..MAX_ITERATIONS {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        1.0 - sum * prefix.exp()
+    } else {
+        // Continued fraction for Q(a, x), by Lentz's method
+        let tiny = f64::MIN_POSITIVE / EPSILON;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..MAX_ITERATIONS {
+            let i = i as f64;
+            let an = -i * (i - a);
+            b += 2.0;
+            d = an.mul_add(d, b);
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < EPSILON {
+                break;
+            }
+        }
+        prefix.exp() * h
+    }
+}
+
+/// Chance of a chi-square statistic at least this big, if the die is fair
+fn chi_square_p(statistic: f64, degrees_of_freedom: f64) -> f64 {
+    gamma_q(degrees_of_freedom / 2.0, statistic / 2.0)
+}
+
+/// Chance of a standard normal value at least this far from zero, either way
+fn normal_two_sided_p(z: f64) -> f64 {
+    // erfc(|z| / √2) = Q(½, z² / 2)
+    gamma_q(0.5, z * z / 2.0)
+}
+
+/// Chance of a Kolmogorov–Smirnov distance at least this big, for large samples
+fn kolmogorov_p(lambda: f64) -> f64 {
+    if lambda < 0.2 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    for j in 1..=100 {
+        let term = (-2.0 * f64::from(j * j) * lambda * lambda).exp();
+        sum += sign * term;
+        if term < EPSILON {
+            break;
+        }
+        sign = -sign;
+    }
+    (2.0 * sum).clamp(0.0, 1.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct TestResult {
+    pub statistic: f64,
+    pub p_value: f64,
+}
+
+/// How often a face came up, compared to how often it should have
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct FaceCount {
+    pub roll: i32,
+    pub observed: i64,
+    pub expected: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FairnessReport {
+    pub die: i32,
+    /// Rolls counted in the stats
+    pub total: i64,
+    pub faces: Vec<FaceCount>,
+    /// Chi-square goodness of fit, if there are enough rolls for it to mean anything
+    pub chi_square: Option<TestResult>,
+    /// Kolmogorov–Smirnov distance from an even spread. Conservative for dice, since
+    /// they only land on whole numbers.
+    pub kolmogorov_smirnov: Option<TestResult>,
+    /// Runs of high and low rolls in the recent history, if there is any
+    pub runs: Option<TestResult>,
+    /// Whether any of the tests came out below `SIGNIFICANCE`, split between the three
+    pub biased: bool,
+}
+
+/// Chi-square test of face counts against an even spread
+#[must_use]
+pub fn chi_square(counts: &[i64]) -> Option<TestResult> {
+    let total: i64 = counts.iter().sum();
+    let expected = total as f64 / counts.len() as f64;
+    if counts.len() < 2 || expected < MIN_EXPECTED {
+        return None;
+    }
+    let statistic = counts
+        .iter()
+        .map(|&observed| (observed as f64 - expected).powi(2) / expected)
+        .sum();
+    Some(TestResult {
+        statistic,
+        p_value: chi_square_p(statistic, (counts.len() - 1) as f64),
+    })
+}
+
+/// Kolmogorov–Smirnov test of face counts against an even spread
+#[must_use]
+pub fn kolmogorov_smirnov(counts: &[i64]) -> Option<TestResult> {
+    let total: i64 = counts.iter().sum();
+    if counts.len() < 2 || total == 0 {
+        return None;
+    }
+    let total = total as f64;
+    let mut seen = 0;
+    let mut distance = 0.0_f64;
+    for (i, &count) in counts.iter().enumerate() {
+        seen += count;
+        let expected = (i + 1) as f64 / counts.len() as f64;
+        distance = distance.max((seen as f64 / total - expected).abs());
+    }
+    let root = total.sqrt();
+    Some(TestResult {
+        statistic: distance,
+        p_value: kolmogorov_p((root + 0.12 + 0.11 / root) * distance),
+    })
+}
+
+/// Wald–Wolfowitz runs test on rolls of a die, split into high and low.
+/// Rolls right in the middle of an odd-sided die are left out.
+#[must_use]
+pub fn runs_test(die: i32, rolls: &[i32]) -> Option<TestResult> {
+    // Compare doubled values, so the middle of the die is a whole number
+    let middle = die + 1;
+    let high: Vec<bool> = rolls
+        .iter()
+        .filter(|&&roll| roll * 2 != middle)
+        .map(|&roll| roll * 2 > middle)
+        .collect();
+    let n1 = high.iter().filter(|&&h| h).count() as f64;
+    let n2 = high.len() as f64 - n1;
+    if n1 < 1.0 || n2 < 1.0 {
+        return None;
+    }
+    let runs = 1 + high.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    let n = n1 + n2;
+    let mean = 2.0 * n1 * n2 / n + 1.0;
+    let variance = 2.0 * n1 * n2 * (2.0 * n1).mul_add(n2, -n) / (n * n * (n - 1.0));
+    if variance <= 0.0 {
+        return None;
+    }
+    let z = (runs as f64 - mean) / variance.sqrt();
+    Some(TestResult {
+        statistic: z,
+        p_value: normal_two_sided_p(z),
+    })
+}
+
+/// Run every test on a die
+#[must_use]
+pub fn report<S: BuildHasher>(
+    die: i32,
+    counts: &HashMap<i32, i64, S>,
+    recent: &[i32],
+) -> FairnessReport {
+    let observed: Vec<i64> = (1..=die)
+        .map(|roll| counts.get(&roll).copied().unwrap_or(0))
+        .collect();
+    let total = observed.iter().sum();
+    let expected = total as f64 / f64::from(die);
+    let chi_square = chi_square(&observed);
+    let kolmogorov_smirnov = kolmogorov_smirnov(&observed);
+    let runs = runs_test(die, recent);
+    let biased = [chi_square, kolmogorov_smirnov, runs]
+        .iter()
+        .flatten()
+        .any(|test| test.p_value < TEST_SIGNIFICANCE);
+    FairnessReport {
+        die,
+        total,
+        faces: (1..=die)
+            .zip(observed)
+            .map(|(roll, observed)| FaceCount {
+                roll,
+                observed,
+                expected,
+            })
+            .collect(),
+        chi_square,
+        kolmogorov_smirnov,
+        runs,
+        biased,
+    }
+}
+
+/// The one size of numbered die in an instruction, if it only rolls one size.
+/// Every die in a roll's history belongs to it then.
+fn single_die(instruction: &str) -> Option<i32> {
+    fn collect(expr: &Expression, sizes: &mut Vec<Option<i32>>) {
+        match expr {
+            Expression::Number(_) => {}
+            Expression::Dice(term) => sizes.push(match term.die {
+                Die::Sides(faces) => Some(faces),
+                Die::Fudge | Die::Faces(_) => None,
+            }),
+            Expression::Negate(expr) => collect(expr, sizes),
+            Expression::Binary(_, left, right) => {
+                collect(left, sizes);
+                collect(right, sizes);
+            }
+        }
+    }
+    let mut sizes = Vec::new();
+    collect(&dice_roller::parse_roll(instruction).ok()?, &mut sizes);
+    let first = *sizes.first()?;
+    sizes
+        .iter()
+        .all(|&size| size == first)
+        .then_some(first)
+        .flatten()
+}
+
+/// Fairness of every numbered die in the stats, or just one
+///
+/// # Errors
+///
+/// Will return an error if the stats or history can't be loaded
+pub fn fairness_reports(conn: &PgConnection, die: Option<i16>) -> QueryResult<Vec<FairnessReport>> {
+    let mut stats = roll_stats::table.into_boxed();
+    if let Some(die) = die {
+        stats = stats.filter(roll_stats::die.eq(die));
+    }
+    let mut counts: BTreeMap<i32, HashMap<i32, i64>> = BTreeMap::new();
+    for stat in stats.load::<RollStat>(conn)? {
+        counts
+            .entry(stat.die.into())
+            .or_default()
+            .insert(stat.roll.into(), stat.roll_count);
+    }
+
+    // Oldest first, so runs are in the order the dice were rolled. Rolls from a seed the
+    // client picked could be anything, so they're left out.
+    let mut history: Vec<RollHistory> = roll_history::table
+        .filter(roll_history::seeded.eq(false))
+        .order(roll_history::id.desc())
+        .limit(RECENT_ROLLS)
+        .load(conn)?;
+    history.reverse();
+    let mut recent: HashMap<i32, Vec<i32>> = HashMap::new();
+    for roll in history {
+        if let Some(die) = single_die(&roll.instruction) {
+            recent.entry(die).or_default().extend(roll.rolls);
+        }
+    }
+
+    Ok(counts
+        .iter()
+        .filter(|(&die, _)| die > 1)
+        .map(|(&die, counts)| report(die, counts, recent.get(&die).map_or(&[][..], Vec::as_slice)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_ln_gamma() {
+        assert!(close(ln_gamma(1.0), 0.0));
+        assert!(close(ln_gamma(5.0), 24f64.ln()));
+        assert!(close(ln_gamma(0.5), PI.sqrt().ln()));
+    }
+
+    #[test]
+    fn test_chi_square_p() {
+        // Critical values from a chi-square table
+        assert!(close(chi_square_p(3.841_459, 1.0), 0.05));
+        assert!(close(chi_square_p(30.143_527, 19.0), 0.05));
+        assert!(close(chi_square_p(36.190_869, 19.0), 0.01));
+        assert!(close(chi_square_p(0.0, 5.0), 1.0));
+        assert!(close(normal_two_sided_p(1.959_964), 0.05));
+    }
+
+    #[test]
+    fn test_chi_square() {
+        assert_eq!(chi_square(&[1, 2, 3, 4, 5, 6]), None);
+        let fair = chi_square(&[100, 100, 100, 100, 100, 100]).unwrap();
+        assert!(close(fair.statistic, 0.0));
+        assert!(close(fair.p_value, 1.0));
+        let loaded = chi_square(&[50, 50, 50, 50, 50, 350]).unwrap();
+        assert!(loaded.p_value < SIGNIFICANCE);
+    }
+
+    #[test]
+    fn test_kolmogorov_smirnov() {
+        let fair = kolmogorov_smirnov(&[100; 20]).unwrap();
+        assert!(close(fair.statistic, 0.0));
+        assert!(close(fair.p_value, 1.0));
+        let mut low = [100; 20];
+        low[..5].copy_from_slice(&[200; 5]);
+        assert!(kolmogorov_smirnov(&low).unwrap().p_value < SIGNIFICANCE);
+    }
+
+    #[test]
+    fn test_runs_test() {
+        assert_eq!(runs_test(6, &[6, 6, 5]), None);
+        // Alternating high and low is far too regular
+        let alternating: Vec<i32> = (0..100).map(|i| if i % 2 == 0 { 1 } else { 6 }).collect();
+        assert!(runs_test(6, &alternating).unwrap().p_value < SIGNIFICANCE);
+        // As is every low roll and then every high roll
+        let clumped: Vec<i32> = (0..100).map(|i| if i < 50 { 2 } else { 5 }).collect();
+        assert!(runs_test(6, &clumped).unwrap().p_value < SIGNIFICANCE);
+        // Middle rolls on odd dice are ignored
+        assert_eq!(runs_test(5, &[3, 3, 3]), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let counts: HashMap<i32, i64> = (1..=20).map(|roll| (roll, 50)).collect();
+        let report = report(20, &counts, &[]);
+        assert_eq!(report.total, 1000);
+        assert_eq!(report.faces.len(), 20);
+        assert!(close(report.faces[0].expected, 50.0));
+        assert!(report.runs.is_none());
+        assert!(!report.biased);
+
+        // Faces that never came up still count
+        let counts: HashMap<i32, i64> = (1..=19).map(|roll| (roll, 50)).collect();
+        let report = super::report(20, &counts, &[]);
+        assert_eq!(report.faces[19].observed, 0);
+        assert!(report.biased);
+
+        // One test a little under 1% isn't enough once it's shared between three
+        let mut counts: HashMap<i32, i64> = (1..=20).map(|roll| (roll, 50)).collect();
+        counts.insert(1, 81);
+        counts.insert(20, 19);
+        let report = super::report(20, &counts, &[]);
+        let chi_square = report.chi_square.unwrap().p_value;
+        assert!(chi_square < SIGNIFICANCE && chi_square > TEST_SIGNIFICANCE);
+        assert!(!report.biased);
+    }
+
+    #[test]
+    fn test_single_die() {
+        assert_eq!(single_die("1d20 + 5"), Some(20));
+        assert_eq!(single_die("4d6dl1"), Some(6));
+        assert_eq!(single_die("1d8 + 1d8"), Some(8));
+        assert_eq!(single_die("1d8 + 1d6"), None);
+        assert_eq!(single_die("4dF"), None);
+        assert_eq!(single_die("5"), None);
+    }
+}
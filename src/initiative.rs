@@ -0,0 +1,405 @@
+//! Initiative order for an encounter, with turns, rounds and effects that wear off
+use crate::{
+    checks,
+    dice_roller::{self, RollError, RollErrorKind, RollLimits, RollResult},
+    r2d2_rng::SeededRng,
+};
+use serde::{Deserialize, Serialize};
+
+/// Whether an effect wears off as a combatant's turn starts or ends
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    StartOfTurn,
+    EndOfTurn,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Combatant {
+    pub id: u32,
+    pub name: String,
+    pub initiative_bonus: i32,
+    /// Dexterity score, to break ties in initiative
+    pub dexterity: i32,
+    /// Initiative total, once it has been rolled or set
+    pub initiative: Option<i32>,
+}
+
+/// A combatant to add to an encounter
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct NewCombatant {
+    pub name: String,
+    #[serde(default)]
+    pub initiative_bonus: i32,
+    #[serde(default = "default_dexterity")]
+    pub dexterity: i32,
+    /// Initiative to use instead of rolling, for players who roll their own
+    pub initiative: Option<i32>,
+}
+
+const fn default_dexterity() -> i32 {
+    10
+}
+
+/// An effect that lasts a number of rounds, counted on one combatant's turns
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Duration {
+    pub id: u32,
+    pub name: String,
+    /// Combatant whose turns count the rounds down
+    pub combatant: u32,
+    /// Rounds left before it wears off
+    pub rounds: u32,
+    pub expires: Expiry,
+}
+
+/// An effect to add to an encounter
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct NewDuration {
+    pub name: String,
+    pub combatant: u32,
+    pub rounds: u32,
+    pub expires: Expiry,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Encounter {
+    pub id: String,
+    /// Current round, or 0 before initiative has been rolled
+    pub round: u32,
+    /// Combatant whose turn it is
+    pub current: Option<u32>,
+    /// Every combatant, in initiative order
+    pub combatants: Vec<Combatant>,
+    pub durations: Vec<Duration>,
+    next_id: u32,
+}
+
+fn invalid(message: &str) -> RollError {
+    RollError::new(RollErrorKind::InvalidEncounter, message)
+}
+
+impl Encounter {
+    #[must_use]
+    pub const fn new(id: String) -> Self {
+        Self {
+            id,
+            round: 0,
+            current: None,
+            combatants: Vec::new(),
+            durations: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    const fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Combatant whose turn it is
+    #[must_use]
+    pub fn current(&self) -> Option<&Combatant> {
+        self.current
+            .and_then(|id| self.combatants.iter().find(|c| c.id == id))
+    }
+
+    /// Combatants who have an initiative, and so take turns
+    fn turn_order(&self) -> Vec<u32> {
+        self.combatants
+            .iter()
+            .filter(|c| c.initiative.is_some())
+            .map(|c| c.id)
+            .collect()
+    }
+
+    /// Highest initiative first, with ties going to the higher Dexterity.
+    /// Anyone still tied stays in the order they were added.
+    fn sort(&mut self) {
+        self.combatants.sort_by(|a, b| {
+            b.initiative
+                .cmp(&a.initiative)
+                .then(b.dexterity.cmp(&a.dexterity))
+        });
+    }
+
+    pub fn add_combatant(&mut self, new: NewCombatant) -> &Combatant {
+        let id = self.next_id();
+        self.combatants.push(Combatant {
+            id,
+            name: new.name,
+            initiative_bonus: new.initiative_bonus,
+            dexterity: new.dexterity,
+            initiative: new.initiative,
+        });
+        self.sort();
+        &self.combatants[self.combatants.iter().position(|c| c.id == id).unwrap_or(0)]
+    }
+
+    /// Take a combatant out of the encounter, along with effects counted on their turns
+    ///
+    /// # Errors
+    ///
+    /// Will return `RollError` if there is no combatant with that id
+    pub fn remove_combatant(&mut self, id: u32) -> Result<Combatant, RollError> {
+        let index = self
+            .combatants
+            .iter()
+            .position(|c| c.id == id)
+            .ok_or_else(|| invalid("There is no combatant with that id."))?;
+        if self.current == Some(id) {
+            // Their turn is cut short, so it passes straight on to the next combatant
+            let order = self.turn_order();
+            let next = order.iter().position(|&c| c == id).map(|i| i + 1);
+            self.current = match next {
+                Some(next) if next < order.len() => Some(order[next]),
+                _ if order.len() > 1 => {
+                    self.round += 1;
+                    Some(order[0])
+                }
+                _ => None,
+            };
+        }
+        self.durations.retain(|d| d.combatant != id);
+        Ok(self.combatants.remove(index))
+    }
+
+    /// Roll initiative for everyone who doesn't have it yet, starting the encounter if needed
+    ///
+    /// # Errors
+    ///
+    /// Will return `RollError` if a combatant's initiative bonus can't be rolled
+    pub fn roll_initiative(
+        &mut self,
+        rng: &mut SeededRng,
+        limits: RollLimits,
+    ) -> Result<Vec<(u32, RollResult)>, RollError> {
+        let mut rolls = Vec::new();
+        for combatant in self
+            .combatants
+            .iter_mut()
+            .filter(|c| c.initiative.is_none())
+        {
+            let roll = dice_roller::roll_seeded(
+                rng,
+                &checks::d20(combatant.initiative_bonus, false, false),
+                limits,
+            )?;
+            combatant.initiative = Some(roll.total);
+            rolls.push((combatant.id, roll));
+        }
+        self.sort();
+        if self.round == 0 {
+            self.current = self.combatants.first().map(|c| c.id);
+            if self.current.is_some() {
+                self.round = 1;
+            }
+        }
+        Ok(rolls)
+    }
+
+    /// Count effects down on one of a combatant's turns, returning any that wore off
+    fn tick(&mut self, combatant: u32, when: Expiry) -> Vec<Duration> {
+        let mut expired = Vec::new();
+        for duration in &mut self.durations {
+            if duration.combatant == combatant && duration.expires == when {
+                duration.rounds = duration.rounds.saturating_sub(1);
+            }
+        }
+        self.durations.retain(|d| {
+            if d.rounds == 0 {
+                expired.push(d.clone());
+            }
+            d.rounds > 0
+        });
+        expired
+    }
+
+    /// End the current turn and start the next one, returning any effects that wore off
+    ///
+    /// # Errors
+    ///
+    /// Will return `RollError` if initiative hasn't been rolled yet
+    pub fn next_turn(&mut self) -> Result<Vec<Duration>, RollError> {
+        let current = self
+            .current
+            .ok_or_else(|| invalid("Roll initiative before taking turns."))?;
+        let mut expired = self.tick(current, Expiry::EndOfTurn);
+
+        let order = self.turn_order();
+        let next = order
+            .iter()
+            .position(|&c| c == current)
+            .map_or(0, |i| i + 1);
+        let next = if next < order.len() {
+            order[next]
+        } else {
+            self.round += 1;
+            order[0]
+        };
+        self.current = Some(next);
+        expired.append(&mut self.tick(next, Expiry::StartOfTurn));
+        Ok(expired)
+    }
+
+    /// Start tracking an effect
+    ///
+    /// # Errors
+    ///
+    /// Will return `RollError` if the combatant doesn't exist, or the effect lasts no rounds
+    pub fn add_duration(&mut self, new: NewDuration) -> Result<&Duration, RollError> {
+        if !self.combatants.iter().any(|c| c.id == new.combatant) {
+            return Err(invalid("There is no combatant with that id."));
+        } else if new.rounds < 1 {
+            return Err(invalid("Effects have to last at least one round."));
+        }
+        let id = self.next_id();
+        self.durations.push(Duration {
+            id,
+            name: new.name,
+            combatant: new.combatant,
+            rounds: new.rounds,
+            expires: new.expires,
+        });
+        Ok(&self.durations[self.durations.len() - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combatant(name: &str, dexterity: i32, initiative: Option<i32>) -> NewCombatant {
+        NewCombatant {
+            name: name.to_string(),
+            initiative_bonus: 0,
+            dexterity,
+            initiative,
+        }
+    }
+
+    fn names(encounter: &Encounter) -> Vec<&str> {
+        encounter
+            .combatants
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_initiative_order() {
+        let mut encounter = Encounter::new(String::from("test"));
+        encounter.add_combatant(combatant("goblin", 14, Some(12)));
+        encounter.add_combatant(combatant("fighter", 10, Some(12)));
+        encounter.add_combatant(combatant("wizard", 14, Some(12)));
+        encounter.add_combatant(combatant("rogue", 18, Some(20)));
+        assert_eq!(names(&encounter), ["rogue", "goblin", "wizard", "fighter"]);
+        assert_eq!(encounter.round, 0);
+        assert!(encounter.current().is_none());
+    }
+
+    #[test]
+    fn test_roll_initiative() {
+        let mut encounter = Encounter::new(String::from("test"));
+        encounter.add_combatant(NewCombatant {
+            initiative_bonus: 3,
+            ..combatant("fighter", 16, None)
+        });
+        encounter.add_combatant(combatant("bard", 12, Some(15)));
+        let rolls = encounter
+            .roll_initiative(&mut SeededRng::new(16), RollLimits::default())
+            .unwrap();
+        assert_eq!(rolls.len(), 1);
+        let (id, roll) = &rolls[0];
+        assert_eq!(*id, 1);
+        assert_eq!(roll.instruction, "1d20 + 3");
+        let fighter = encounter.combatants.iter().find(|c| c.id == 1).unwrap();
+        assert_eq!(fighter.initiative, Some(roll.total));
+        assert_eq!(encounter.round, 1);
+        assert_eq!(encounter.current, Some(encounter.combatants[0].id));
+    }
+
+    #[test]
+    fn test_next_turn() {
+        let mut encounter = Encounter::new(String::from("test"));
+        assert!(encounter.next_turn().is_err());
+        encounter.add_combatant(combatant("a", 10, Some(20)));
+        encounter.add_combatant(combatant("b", 10, Some(10)));
+        encounter
+            .roll_initiative(&mut SeededRng::new(16), RollLimits::default())
+            .unwrap();
+        assert_eq!(encounter.current().unwrap().name, "a");
+        encounter.next_turn().unwrap();
+        assert_eq!(encounter.current().unwrap().name, "b");
+        assert_eq!(encounter.round, 1);
+        encounter.next_turn().unwrap();
+        assert_eq!(encounter.current().unwrap().name, "a");
+        assert_eq!(encounter.round, 2);
+
+        // Joining mid-fight without initiative means waiting for it to be rolled
+        encounter.add_combatant(combatant("c", 10, None));
+        encounter.next_turn().unwrap();
+        encounter.next_turn().unwrap();
+        assert_eq!(encounter.current().unwrap().name, "a");
+        assert_eq!(encounter.round, 3);
+    }
+
+    #[test]
+    fn test_durations() {
+        let mut encounter = Encounter::new(String::from("test"));
+        let a = encounter.add_combatant(combatant("a", 10, Some(20))).id;
+        let b = encounter.add_combatant(combatant("b", 10, Some(10))).id;
+        encounter
+            .roll_initiative(&mut SeededRng::new(16), RollLimits::default())
+            .unwrap();
+        // Cast on a's turn, lasting until the start of a's next turn
+        encounter
+            .add_duration(NewDuration {
+                name: String::from("bless"),
+                combatant: a,
+                rounds: 1,
+                expires: Expiry::StartOfTurn,
+            })
+            .unwrap();
+        // Until the end of b's next turn
+        encounter
+            .add_duration(NewDuration {
+                name: String::from("frightened"),
+                combatant: b,
+                rounds: 1,
+                expires: Expiry::EndOfTurn,
+            })
+            .unwrap();
+        assert!(encounter.next_turn().unwrap().is_empty());
+        let expired = encounter.next_turn().unwrap();
+        let expired: Vec<&str> = expired.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(expired, ["frightened", "bless"]);
+        assert!(encounter.durations.is_empty());
+
+        assert!(encounter
+            .add_duration(NewDuration {
+                name: String::from("nothing"),
+                combatant: 99,
+                rounds: 1,
+                expires: Expiry::EndOfTurn,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_remove_combatant() {
+        let mut encounter = Encounter::new(String::from("test"));
+        let a = encounter.add_combatant(combatant("a", 10, Some(20))).id;
+        let b = encounter.add_combatant(combatant("b", 10, Some(10))).id;
+        encounter
+            .roll_initiative(&mut SeededRng::new(16), RollLimits::default())
+            .unwrap();
+        encounter.next_turn().unwrap();
+        assert_eq!(encounter.current, Some(b));
+        encounter.remove_combatant(b).unwrap();
+        assert_eq!(encounter.current, Some(a));
+        assert_eq!(encounter.round, 2);
+        assert!(encounter.remove_combatant(b).is_err());
+    }
+}
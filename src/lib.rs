@@ -4,6 +4,7 @@
 #[macro_use]
 extern crate diesel;
 
+use dice_roller::{RollLimits, DEFAULT_MAX_DIE};
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
 use r2d2_redis::RedisConnectionManager;
@@ -11,20 +12,38 @@ use r2d2_rng::RngConnectionManager;
 use sentry::{self, ClientInitGuard};
 use std::env;
 
+pub mod auth;
+pub mod character;
+pub mod checks;
 pub mod dice_roller;
+pub mod fairness;
+pub mod initiative;
 pub mod models;
+pub mod probability;
+pub mod provably_fair;
 pub mod r2d2_rng;
 pub mod schema;
+pub mod stats;
 
 pub const REDIS_KEY_ROLL_STATS: &str = "roll_stats";
+/// Dice kept from rolls with advantage, kept apart since they aren't evenly spread
+pub const REDIS_KEY_ADVANTAGE_STATS: &str = "roll_stats:advantage";
+pub const REDIS_KEY_DISADVANTAGE_STATS: &str = "roll_stats:disadvantage";
+pub const REDIS_KEY_FAIR_SESSION: &str = "fair_session";
+pub const REDIS_KEY_ENCOUNTER: &str = "encounter";
 
 pub fn sentry_init() -> ClientInitGuard {
-    env::set_var("RUST_BACKTRACE", "1");
-    let guard = sentry::init("https://046b94f8170f4135a47ca9d0f9709a6d@sentry.io/1438468");
+    let guard = sentry_init_quiet();
     tide::log::start();
     guard
 }
 
+/// Report errors without starting the logger, which writes to stdout
+pub fn sentry_init_quiet() -> ClientInitGuard {
+    env::set_var("RUST_BACKTRACE", "1");
+    sentry::init("https://046b94f8170f4135a47ca9d0f9709a6d@sentry.io/1438468")
+}
+
 #[must_use]
 pub fn db_pool() -> Pool<ConnectionManager<PgConnection>> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -55,3 +74,32 @@ pub fn rng_pool() -> Pool<RngConnectionManager> {
         .build(manager)
         .unwrap_or_else(|_| panic!("Error creating rngs"))
 }
+
+/// Secret for signing login tokens, from the `JWT_SECRET` environment variable
+///
+/// # Panics
+///
+/// Will panic if `JWT_SECRET` isn't set
+#[must_use]
+pub fn jwt_secret() -> Vec<u8> {
+    env::var("JWT_SECRET")
+        .expect("JWT_SECRET must be set")
+        .into_bytes()
+}
+
+/// Bounds on rolls, configured with the `MAX_DIE` environment variable
+///
+/// # Panics
+///
+/// Will panic if `MAX_DIE` is set to something other than a number up to 32767
+#[must_use]
+pub fn roll_limits() -> RollLimits {
+    // Stats are stored per face in a SMALLINT column, so dice can't go past i16
+    let max_die: i16 = env::var("MAX_DIE")
+        .unwrap_or_else(|_| DEFAULT_MAX_DIE.to_string())
+        .parse()
+        .expect("MAX_DIE must be a number no bigger than 32767");
+    RollLimits {
+        max_die: max_die.into(),
+    }
+}
@@ -1,7 +1,10 @@
-use crate::schema::roll_stats;
-use chrono::NaiveDateTime;
+use crate::schema::{
+    advantage_roll_stats, roll_history, roll_stats, room_gms, rooms, scoped_roll_stats, users,
+};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Serialize;
 
-#[derive(Debug, Identifiable, Queryable)]
+#[derive(Debug, Identifiable, Queryable, Serialize)]
 #[primary_key(die, roll)]
 pub struct RollStat {
     pub die: i16,
@@ -9,3 +12,128 @@ pub struct RollStat {
     pub roll_count: i64,
     pub updated_at: NaiveDateTime,
 }
+
+#[derive(Debug, Insertable)]
+#[table_name = "roll_stats"]
+pub struct NewRollStat {
+    pub die: i16,
+    pub roll: i16,
+    pub roll_count: i64,
+}
+
+/// Stats for the die kept from rolls with advantage or disadvantage
+#[derive(Debug, Identifiable, Queryable, Serialize)]
+#[primary_key(mode, die, roll)]
+pub struct AdvantageRollStat {
+    pub mode: String,
+    pub die: i16,
+    pub roll: i16,
+    pub roll_count: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "advantage_roll_stats"]
+pub struct NewAdvantageRollStat<'a> {
+    pub mode: &'a str,
+    pub die: i16,
+    pub roll: i16,
+    pub roll_count: i64,
+}
+
+/// Stats for one user or room over a day or week
+#[derive(Debug, Identifiable, Queryable, Serialize)]
+#[primary_key(scope, scope_id, bucket, bucket_start, die, roll)]
+pub struct ScopedRollStat {
+    pub scope: String,
+    pub scope_id: String,
+    pub bucket: String,
+    pub bucket_start: NaiveDate,
+    pub die: i16,
+    pub roll: i16,
+    pub roll_count: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "scoped_roll_stats"]
+pub struct NewScopedRollStat<'a> {
+    pub scope: &'a str,
+    pub scope_id: &'a str,
+    pub bucket: &'a str,
+    pub bucket_start: NaiveDate,
+    pub die: i16,
+    pub roll: i16,
+    pub roll_count: i64,
+}
+
+/// A single roll, kept so it can be looked back on later
+#[derive(Debug, Identifiable, Queryable, Serialize)]
+#[table_name = "roll_history"]
+pub struct RollHistory {
+    pub id: i64,
+    pub instruction: String,
+    pub rolls: Vec<i32>,
+    pub total: i32,
+    pub room: Option<String>,
+    pub user_name: Option<String>,
+    pub private: bool,
+    pub created_at: NaiveDateTime,
+    pub user_id: Option<i32>,
+    /// Rolled from a seed the client picked
+    pub seeded: bool,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "roll_history"]
+pub struct NewRollHistory<'a> {
+    pub instruction: &'a str,
+    pub rolls: &'a [i32],
+    pub total: i32,
+    pub room: Option<&'a str>,
+    pub user_name: Option<&'a str>,
+    pub private: bool,
+    pub user_id: Option<i32>,
+    pub seeded: bool,
+}
+
+#[derive(Debug, Identifiable, Queryable, Serialize)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub password_hash: &'a str,
+}
+
+/// A room players can share their rolls in, run by whoever opened it
+#[derive(Debug, Identifiable, Queryable, Serialize)]
+#[primary_key(name)]
+pub struct Room {
+    pub name: String,
+    pub owner_id: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "rooms"]
+pub struct NewRoom<'a> {
+    pub name: &'a str,
+    pub owner_id: i32,
+}
+
+/// A member who can see every roll in a room, private or not
+#[derive(Debug, Insertable)]
+#[table_name = "room_gms"]
+pub struct NewRoomGm<'a> {
+    pub room: &'a str,
+    pub user_id: i32,
+}
@@ -0,0 +1,631 @@
+//! Exact odds for roll expressions, worked out by convolution rather than by sampling
+//!
+//! Any `RollInstruction` can be turned into an `Expression` with `.into()`.
+use crate::dice_roller::{
+    self, Condition, DiceTerm, Die, ExplodeKind, Expression, Operator, RollError, RollErrorKind,
+    RollLimits, Selection, SuccessPool, MAX_EXPLOSIONS, MAX_REROLLS,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, convert::TryFrom};
+
+// Explosions are followed until the chance of reaching them is smaller than this
+const NEGLIGIBLE: f64 = 1e-16;
+// Most combinations of outcomes that will be looked at for a single expression
+const MAX_WORK: usize = 5_000_000;
+// Percentiles included in a summary
+const PERCENTILES: [u8; 7] = [5, 10, 25, 50, 75, 90, 95];
+
+/// Keeps track of how much work is left before a roll is too big to work out
+struct Budget {
+    remaining: usize,
+}
+
+impl Budget {
+    fn spend(&mut self, work: usize) -> Result<(), RollError> {
+        self.remaining = self.remaining.checked_sub(work).ok_or_else(|| {
+            RollError::new(
+                RollErrorKind::TooComplex,
+                "That roll has too many outcomes to work out the odds!",
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// Chance of every possible total of a roll
+#[derive(Clone, Debug, PartialEq)]
+pub struct Distribution {
+    outcomes: BTreeMap<i32, f64>,
+}
+
+impl Distribution {
+    fn constant(total: i32) -> Self {
+        let mut outcomes = BTreeMap::new();
+        outcomes.insert(total, 1.0);
+        Self { outcomes }
+    }
+
+    fn from_weights(weights: impl IntoIterator<Item = (i32, f64)>) -> Self {
+        let mut outcomes = BTreeMap::new();
+        for (total, probability) in weights {
+            if probability > 0.0 {
+                *outcomes.entry(total).or_default() += probability;
+            }
+        }
+        Self { outcomes }
+    }
+
+    /// Apply a function to every total
+    fn map(&self, f: impl Fn(i32) -> Result<i32, RollError>) -> Result<Self, RollError> {
+        let mut weights = Vec::with_capacity(self.outcomes.len());
+        for (total, probability) in self.outcomes() {
+            weights.push((f(total)?, probability));
+        }
+        Ok(Self::from_weights(weights))
+    }
+
+    /// Combine the totals of two independent rolls
+    fn combine(
+        &self,
+        other: &Self,
+        budget: &mut Budget,
+        op: impl Fn(i32, i32) -> Result<i32, RollError>,
+    ) -> Result<Self, RollError> {
+        budget.spend(self.outcomes.len() * other.outcomes.len())?;
+        let mut weights = Vec::new();
+        for (lhs, p) in self.outcomes() {
+            for (rhs, q) in other.outcomes() {
+                weights.push((op(lhs, rhs)?, p * q));
+            }
+        }
+        Ok(Self::from_weights(weights))
+    }
+
+    /// Every possible total, lowest first, with its probability
+    pub fn outcomes(&self) -> impl Iterator<Item = (i32, f64)> + '_ {
+        self.outcomes.iter().map(|(&total, &p)| (total, p))
+    }
+
+    /// Probability of rolling exactly `total`
+    #[must_use]
+    pub fn probability(&self, total: i32) -> f64 {
+        self.outcomes.get(&total).copied().unwrap_or_default()
+    }
+
+    /// Lowest possible total
+    #[must_use]
+    pub fn min(&self) -> i32 {
+        self.outcomes.keys().next().copied().unwrap_or_default()
+    }
+
+    /// Highest possible total
+    #[must_use]
+    pub fn max(&self) -> i32 {
+        self.outcomes
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Average total
+    #[must_use]
+    pub fn mean(&self) -> f64 {
+        self.outcomes().map(|(total, p)| f64::from(total) * p).sum()
+    }
+
+    /// How spread out the totals are around the mean
+    #[must_use]
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        self.outcomes()
+            .map(|(total, p)| (f64::from(total) - mean).powi(2) * p)
+            .sum()
+    }
+
+    #[must_use]
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    /// Lowest total that at least `percent`% of rolls come in at or under
+    #[must_use]
+    pub fn percentile(&self, percent: f64) -> i32 {
+        let target = percent / 100.0;
+        let mut cumulative = 0.0;
+        for (total, p) in self.outcomes() {
+            cumulative += p;
+            // Leave a little room for rounding errors in the sums
+            if cumulative >= target - 1e-12 {
+                return total;
+            }
+        }
+        self.max()
+    }
+
+    /// Probability of rolling `target` or more
+    #[must_use]
+    pub fn at_least(&self, target: i32) -> f64 {
+        self.outcomes.range(target..).map(|(_, p)| p).sum()
+    }
+}
+
+/// Chance of a single total
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Outcome {
+    pub total: i32,
+    pub probability: f64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Percentile {
+    pub percentile: u8,
+    pub total: i32,
+}
+
+/// Statistics for a roll expression, ready to be sent back to a user
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// The instruction the statistics are for
+    pub instruction: String,
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+    pub variance: f64,
+    pub std_dev: f64,
+    pub percentiles: Vec<Percentile>,
+    /// Every possible total, lowest first
+    pub distribution: Vec<Outcome>,
+    /// Chance of rolling at least the target total, if one was asked for
+    pub at_least: Option<Outcome>,
+}
+
+/// Work out the exact chance of every total an expression can roll
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression can't be rolled, or has too many outcomes to work out
+pub fn distribution(expr: &Expression, limits: RollLimits) -> Result<Distribution, RollError> {
+    let mut budget = Budget {
+        remaining: MAX_WORK,
+    };
+    evaluate(expr, limits, &mut budget)
+}
+
+/// Summarize the odds of an expression, including the chance of rolling at least `target`
+///
+/// # Errors
+///
+/// Will return `RollError` if the expression can't be rolled, or has too many outcomes to work out
+pub fn summarize(
+    expr: &Expression,
+    limits: RollLimits,
+    target: Option<i32>,
+) -> Result<Summary, RollError> {
+    let dist = distribution(expr, limits)?;
+    Ok(Summary {
+        instruction: expr.to_string(),
+        min: dist.min(),
+        max: dist.max(),
+        mean: dist.mean(),
+        variance: dist.variance(),
+        std_dev: dist.std_dev(),
+        percentiles: PERCENTILES
+            .iter()
+            .map(|&percentile| Percentile {
+                percentile,
+                total: dist.percentile(f64::from(percentile)),
+            })
+            .collect(),
+        distribution: dist
+            .outcomes()
+            .map(|(total, probability)| Outcome { total, probability })
+            .collect(),
+        at_least: target.map(|total| Outcome {
+            total,
+            probability: dist.at_least(total),
+        }),
+    })
+}
+
+fn evaluate(
+    expr: &Expression,
+    limits: RollLimits,
+    budget: &mut Budget,
+) -> Result<Distribution, RollError> {
+    match expr {
+        Expression::Number(n) => Ok(Distribution::constant(*n)),
+        Expression::Dice(dice) => dice_distribution(dice, limits, budget),
+        Expression::Negate(inner) => evaluate(inner, limits, budget)?
+            .map(|total| total.checked_neg().ok_or_else(dice_roller::overflow)),
+        Expression::Binary(op, lhs, rhs) => {
+            let lhs = evaluate(lhs, limits, budget)?;
+            let rhs = evaluate(rhs, limits, budget)?;
+            match op {
+                Operator::Add => lhs.combine(&rhs, budget, |a, b| {
+                    a.checked_add(b).ok_or_else(dice_roller::overflow)
+                }),
+                Operator::Subtract => lhs.combine(&rhs, budget, |a, b| {
+                    a.checked_sub(b).ok_or_else(dice_roller::overflow)
+                }),
+                Operator::Multiply => lhs.combine(&rhs, budget, |a, b| {
+                    a.checked_mul(b).ok_or_else(dice_roller::overflow)
+                }),
+                Operator::Divide => lhs.combine(&rhs, budget, dice_roller::div_floor),
+            }
+        }
+    }
+}
+
+/// Every face of a die is equally likely
+fn face_distribution(die: &Die) -> Distribution {
+    let values = die.values();
+    // Dice have already been checked to have a reasonable number of faces
+    let weight = 1.0 / f64::from(u32::try_from(values.len()).unwrap_or(u32::MAX));
+    Distribution::from_weights(values.into_iter().map(|value| (value, weight)))
+}
+
+/// Chance of each value a single die lands on once any rerolls are done
+fn reroll_distribution(dice: &DiceTerm) -> Distribution {
+    let base = face_distribution(&dice.die);
+    let Some(reroll) = dice.reroll else {
+        return base;
+    };
+    let chance: f64 = base
+        .outcomes()
+        .filter(|&(value, _)| reroll.condition.matches(value))
+        .map(|(_, p)| p)
+        .sum();
+    let limit = if reroll.once { 1 } else { MAX_REROLLS };
+    // Each reroll either keeps the first value, or starts over with one less reroll to go
+    let mut current = base.clone();
+    for _ in 0..limit {
+        current = Distribution::from_weights(base.outcomes().map(|(value, p)| {
+            let kept = if reroll.condition.matches(value) {
+                0.0
+            } else {
+                p
+            };
+            (value, chance.mul_add(current.probability(value), kept))
+        }));
+    }
+    current
+}
+
+/// Contribution of a single die to a success pool
+fn pool_score(pool: SuccessPool, value: i32) -> i32 {
+    let mut score = 0;
+    if pool.success.matches(value) {
+        score += 1;
+        if pool.double.is_some_and(|d| d.matches(value)) {
+            score += 1;
+        }
+    }
+    if pool.failure.is_some_and(|f| f.matches(value)) {
+        score -= 1;
+    }
+    score
+}
+
+/// Total of a die and every extra die it explodes into.
+/// `contribution` is given each value rolled, and whether it came from an explosion.
+fn explode(
+    single: &Distribution,
+    condition: Condition,
+    contribution: impl Fn(i32, bool) -> i32,
+    budget: &mut Budget,
+) -> Result<Distribution, RollError> {
+    let chance: f64 = single
+        .outcomes()
+        .filter(|&(value, _)| condition.matches(value))
+        .map(|(_, p)| p)
+        .sum();
+    let mut depth = 0;
+    let mut reach = 1.0;
+    while depth < MAX_EXPLOSIONS {
+        reach *= chance;
+        if reach < NEGLIGIBLE {
+            break;
+        }
+        depth += 1;
+    }
+
+    // Work up from the deepest explosion that is still likely enough to matter
+    let mut below: Option<Distribution> = None;
+    for level in (0..=depth).rev() {
+        let extra = level > 0;
+        let mut weights = Vec::new();
+        for (value, p) in single.outcomes() {
+            let total = contribution(value, extra);
+            match below.as_ref().filter(|_| condition.matches(value)) {
+                Some(below) => {
+                    budget.spend(below.outcomes.len())?;
+                    for (rest, q) in below.outcomes() {
+                        let sum = total.checked_add(rest).ok_or_else(dice_roller::overflow)?;
+                        weights.push((sum, p * q));
+                    }
+                }
+                None => weights.push((total, p)),
+            }
+        }
+        below = Some(Distribution::from_weights(weights));
+    }
+    Ok(below.unwrap_or_else(|| single.clone()))
+}
+
+fn dice_distribution(
+    dice: &DiceTerm,
+    limits: RollLimits,
+    budget: &mut Budget,
+) -> Result<Distribution, RollError> {
+    let explosion = dice_roller::validate_dice(dice, limits)?;
+    let single = reroll_distribution(dice);
+    let score = |value: i32| dice.pool.map_or(value, |pool| pool_score(pool, value));
+    let (num, selection) = dice.rolled();
+    let num = usize::try_from(num).unwrap_or_default();
+
+    if let Some(selection) = selection {
+        let per_die = match explosion {
+            None => single,
+            Some((ExplodeKind::Compound, condition)) => {
+                explode(&single, condition, |value, _| value, budget)?
+            }
+            Some(_) => {
+                return Err(RollError::new(
+                    RollErrorKind::Unsupported,
+                    "Can't work out the odds of keeping dice that explode into more dice.",
+                )
+                .with_suggestions(&["!!"]))
+            }
+        };
+        return select(&per_die, num, selection, score, budget);
+    }
+
+    let per_die = match explosion {
+        None => single.map(|value| Ok(score(value)))?,
+        Some((ExplodeKind::Compound, condition)) => {
+            explode(&single, condition, |value, _| value, budget)?.map(|value| Ok(score(value)))?
+        }
+        Some((ExplodeKind::Explode, condition)) => {
+            explode(&single, condition, |value, _| score(value), budget)?
+        }
+        Some((ExplodeKind::Penetrate, condition)) => explode(
+            &single,
+            condition,
+            |value, extra| {
+                score(if extra {
+                    value.saturating_sub(1)
+                } else {
+                    value
+                })
+            },
+            budget,
+        )?,
+    };
+    let mut total = Distribution::constant(0);
+    for _ in 0..num {
+        total = total.combine(&per_die, budget, |a, b| {
+            a.checked_add(b).ok_or_else(dice_roller::overflow)
+        })?;
+    }
+    Ok(total)
+}
+
+/// Total of the dice left after keeping or dropping some of them.
+///
+/// Goes through the values from the end being kept, choosing how many dice land on each value,
+/// so every ordering of the dice is counted once with the right weight.
+fn select(
+    die: &Distribution,
+    num: usize,
+    selection: Selection,
+    score: impl Fn(i32) -> i32,
+    budget: &mut Budget,
+) -> Result<Distribution, RollError> {
+    let count = |n: i32| usize::try_from(n).unwrap_or(0).min(num);
+    let (keep, highest) = match selection {
+        Selection::KeepHighest(n) => (count(n), true),
+        Selection::KeepLowest(n) => (count(n), false),
+        Selection::DropHighest(n) => (num - count(n), false),
+        Selection::DropLowest(n) => (num - count(n), true),
+    };
+
+    // Pascal's triangle, for the number of ways to pick which dice land on a value
+    let mut binomial = vec![vec![1.0; 1]; num + 1];
+    for n in 1..=num {
+        let mut row = vec![1.0; n + 1];
+        for k in 1..n {
+            row[k] = binomial[n - 1][k - 1] + binomial[n - 1][k];
+        }
+        binomial[n] = row;
+    }
+
+    let mut values: Vec<(i32, f64)> = die.outcomes().collect();
+    if highest {
+        values.reverse();
+    }
+    // Chance of each kept total, indexed by how many dice have been placed so far
+    let mut placed: Vec<BTreeMap<i32, f64>> = vec![BTreeMap::new(); num + 1];
+    placed[0].insert(0, 1.0);
+    for (value, p) in values {
+        let value = score(value);
+        let mut next: Vec<BTreeMap<i32, f64>> = vec![BTreeMap::new(); num + 1];
+        for (used, totals) in placed.iter().enumerate() {
+            let left = num - used;
+            budget.spend(totals.len() * (left + 1))?;
+            for (&total, &chance) in totals {
+                let mut power = 1.0;
+                for landed in 0..=left {
+                    let kept = landed.min(keep.saturating_sub(used));
+                    let added = i32::try_from(kept)
+                        .ok()
+                        .and_then(|kept| value.checked_mul(kept))
+                        .and_then(|added| total.checked_add(added))
+                        .ok_or_else(dice_roller::overflow)?;
+                    *next[used + landed].entry(added).or_default() +=
+                        chance * binomial[left][landed] * power;
+                    power *= p;
+                }
+            }
+        }
+        placed = next;
+    }
+    Ok(Distribution::from_weights(placed.pop().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice_roller::{parse_roll, RollInstruction};
+
+    fn odds(cmd: &str) -> Distribution {
+        distribution(&parse_roll(cmd).unwrap(), RollLimits::default()).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_single_die() {
+        let dist = odds("1d20");
+        assert_eq!(dist.min(), 1);
+        assert_eq!(dist.max(), 20);
+        assert_close(dist.probability(7), 0.05);
+        assert_close(dist.mean(), 10.5);
+        assert_close(dist.variance(), 33.25);
+        assert_close(dist.at_least(15), 0.3);
+    }
+
+    #[test]
+    fn test_instruction() {
+        let instruction = RollInstruction {
+            num: 2,
+            die: 6,
+            modifier: -2,
+        };
+        let dist = distribution(&instruction.into(), RollLimits::default()).unwrap();
+        assert_eq!(dist.min(), 0);
+        assert_eq!(dist.max(), 10);
+        assert_close(dist.probability(5), 6.0 / 36.0);
+        assert_close(dist.mean(), 5.0);
+        assert_eq!(dist.percentile(50.0), 5);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let dist = odds("(1d4 - 1) * 2 / 3");
+        // (0, 2, 4, 6) / 3 rounded down
+        assert_close(dist.probability(0), 0.5);
+        assert_close(dist.probability(1), 0.25);
+        assert_close(dist.probability(2), 0.25);
+        let dist = odds("-1d4");
+        assert_eq!((dist.min(), dist.max()), (-4, -1));
+    }
+
+    #[test]
+    fn test_keep_highest() {
+        let dist = odds("4d6dl1");
+        assert_eq!((dist.min(), dist.max()), (3, 18));
+        assert_close(dist.probability(18), 21.0 / 1296.0);
+        assert_close(dist.probability(3), 1.0 / 1296.0);
+        assert_close(dist.mean(), 15869.0 / 1296.0);
+        assert_eq!(odds("2d20kh1"), odds("2d20dl1"));
+        assert_close(odds("2d20kh1").probability(20), 39.0 / 400.0);
+        assert_close(odds("2d20kl1").probability(20), 1.0 / 400.0);
+    }
+
+    #[test]
+    fn test_advantage() {
+        let dist = odds("1d20adv");
+        assert_eq!(dist, odds("2d20kh1"));
+        assert_close(dist.at_least(11), 0.75);
+        assert_close(odds("1d20dis").at_least(11), 0.25);
+        assert_close(odds("1d20adv3").probability(1), 1.0 / 8000.0);
+        assert_eq!(odds("1d20advdis"), odds("1d20"));
+    }
+
+    #[test]
+    fn test_reroll() {
+        let dist = odds("1d6r1");
+        assert_close(dist.probability(1), 1.0 / 36.0);
+        assert_close(dist.probability(6), 7.0 / 36.0);
+        let dist = odds("1d6rr<2");
+        assert_close(dist.probability(1), 0.0);
+        assert_close(dist.probability(2), 0.2);
+    }
+
+    #[test]
+    fn test_explode() {
+        let dist = odds("1d6!");
+        assert_close(dist.probability(6), 0.0);
+        assert_close(dist.probability(7), 1.0 / 36.0);
+        assert_close(dist.probability(13), 1.0 / 216.0);
+        assert_close(dist.mean(), 4.2);
+        assert_eq!(odds("1d6!"), odds("1d6!!"));
+        let dist = odds("1d6!p");
+        assert_close(dist.probability(6), 1.0 / 36.0);
+        assert_close(dist.probability(10), 1.0 / 36.0);
+        assert_close(dist.probability(11), 1.0 / 216.0);
+    }
+
+    #[test]
+    fn test_compound_keep() {
+        let dist = odds("2d6!!kh1");
+        assert_close(dist.outcomes().map(|(_, p)| p).sum(), 1.0);
+        assert_close(dist.probability(1), 1.0 / 36.0);
+        assert!(distribution(&parse_roll("2d6!kh1").unwrap(), RollLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_success_pool() {
+        let dist = odds("3d10>=8");
+        assert_close(dist.probability(3), 0.027);
+        assert_close(dist.probability(0), 0.343);
+        let dist = odds("1d10>=8f1t10");
+        assert_close(dist.probability(-1), 0.1);
+        assert_close(dist.probability(2), 0.1);
+        assert_close(dist.probability(1), 0.2);
+    }
+
+    #[test]
+    fn test_custom_dice() {
+        let dist = odds("4dF");
+        assert_eq!((dist.min(), dist.max()), (-4, 4));
+        assert_close(dist.probability(0), 19.0 / 81.0);
+        let dist = odds("1d{1,1,2}");
+        assert_close(dist.probability(1), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let limits = RollLimits::default();
+        assert!(distribution(&parse_roll("1d4 / (1d4 - 1)").unwrap(), limits).is_err());
+        assert!(distribution(&parse_roll("4d6kh0").unwrap(), limits).is_err());
+        assert!(distribution(&parse_roll("99d1000kh50").unwrap(), limits).is_err());
+        assert!(distribution(&parse_roll("2000000000d20dis").unwrap(), limits).is_err());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let summary =
+            summarize(&parse_roll("2d6").unwrap(), RollLimits::default(), Some(8)).unwrap();
+        assert_eq!(summary.instruction, "2d6");
+        assert_eq!(summary.distribution.len(), 11);
+        assert_eq!(
+            summary.percentiles[3],
+            Percentile {
+                percentile: 50,
+                total: 7
+            }
+        );
+        let at_least = summary.at_least.unwrap();
+        assert_eq!(at_least.total, 8);
+        assert_close(at_least.probability, 15.0 / 36.0);
+    }
+}
@@ -0,0 +1,155 @@
+//! Commit-reveal rolling, so players can check the server didn't pick their rolls
+//!
+//! 1. The server picks a secret seed and publishes its SHA-256 hash as a commitment.
+//! 2. Each roll mixes in a seed from the client and a nonce that counts up with every roll.
+//! 3. Once the session is over the server reveals its seed, and every roll can be checked
+//!    with `verify_roll`.
+use crate::{
+    dice_roller::{self, RollError, RollLimits, RollResult},
+    r2d2_rng::SeededRng,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A roll made from a committed server seed, with everything needed to check it later
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FairRoll {
+    /// SHA-256 hash of the server seed, published before any rolls
+    pub commitment: String,
+    pub client_seed: String,
+    pub nonce: u64,
+    /// The instruction that was rolled
+    pub instruction: String,
+    /// The results of all rolls made
+    pub rolls: Vec<i32>,
+    /// The total value of the entire roll
+    pub total: i32,
+}
+
+/// Pick a new secret server seed
+pub fn server_seed(rng: &mut impl RngCore) -> String {
+    let mut bytes = [0; 32];
+    rng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hash of a server seed that can be published without giving the seed away
+#[must_use]
+pub fn commitment(server_seed: &str) -> String {
+    hex::encode(Sha256::digest(server_seed.as_bytes()))
+}
+
+/// Seed for the random stream of a single roll, mixed from both sides' seeds
+#[must_use]
+pub fn roll_seed(server_seed: &str, client_seed: &str, nonce: u64) -> u64 {
+    let hash = Sha256::digest(format!("{}:{}:{}", server_seed, client_seed, nonce).as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Roll an instruction from a server seed, client seed and nonce
+///
+/// # Errors
+///
+/// Will return `RollError` if the instruction is invalid
+pub fn fair_roll(
+    server_seed: &str,
+    client_seed: &str,
+    nonce: u64,
+    instruction: &str,
+    limits: RollLimits,
+) -> Result<(FairRoll, RollResult), RollError> {
+    let expr = dice_roller::parse_roll(instruction)?;
+    let mut rng = SeededRng::new(roll_seed(server_seed, client_seed, nonce));
+    let result = dice_roller::roll_seeded(&mut rng, &expr, limits)?;
+    let fair = FairRoll {
+        commitment: commitment(server_seed),
+        client_seed: client_seed.to_string(),
+        nonce,
+        instruction: result.instruction.clone(),
+        rolls: result.rolls.clone(),
+        total: result.total,
+    };
+    Ok((fair, result))
+}
+
+/// Check a roll against the server seed revealed at the end of a session.
+/// True if the seed matches the commitment and rolls the same dice again.
+///
+/// # Errors
+///
+/// Will return `RollError` if the instruction in the roll is invalid
+pub fn verify_roll(
+    server_seed: &str,
+    roll: &FairRoll,
+    limits: RollLimits,
+) -> Result<bool, RollError> {
+    if commitment(server_seed) != roll.commitment {
+        return Ok(false);
+    }
+    let (replayed, _) = fair_roll(
+        server_seed,
+        &roll.client_seed,
+        roll.nonce,
+        &roll.instruction,
+        limits,
+    )?;
+    Ok(&replayed == roll)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn test_commitment() {
+        let mut rng = Pcg64::from_entropy();
+        let seed = server_seed(&mut rng);
+        assert_eq!(seed.len(), 64);
+        assert_eq!(commitment(&seed), commitment(&seed));
+        assert_ne!(commitment(&seed), commitment(&server_seed(&mut rng)));
+        assert_eq!(
+            commitment("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_roll_seed() {
+        assert_eq!(
+            roll_seed("server", "client", 1),
+            roll_seed("server", "client", 1)
+        );
+        assert_ne!(
+            roll_seed("server", "client", 1),
+            roll_seed("server", "client", 2)
+        );
+        assert_ne!(
+            roll_seed("server", "client", 1),
+            roll_seed("server", "other", 1)
+        );
+    }
+
+    #[test]
+    fn test_verify_roll() {
+        let limits = RollLimits::default();
+        let (roll, result) = fair_roll("server", "client", 3, "4d6dl1 + 2", limits).unwrap();
+        assert_eq!(roll.total, result.total);
+        assert_eq!(roll.commitment, commitment("server"));
+        assert!(verify_roll("server", &roll, limits).unwrap());
+        // Wrong seed revealed
+        assert!(!verify_roll("other", &roll, limits).unwrap());
+        // Tampered results
+        let tampered = FairRoll {
+            total: roll.total + 1,
+            ..roll.clone()
+        };
+        assert!(!verify_roll("server", &tampered, limits).unwrap());
+        let tampered = FairRoll { nonce: 4, ..roll };
+        assert!(!verify_roll("server", &tampered, limits).unwrap());
+    }
+}
@@ -1,6 +1,84 @@
 use r2d2::ManageConnection;
-use rand::{Error, SeedableRng};
+use rand::{rngs::OsRng, Error, RngCore, SeedableRng};
 use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// Where a roll started in a random stream, so it can be rolled again
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RollSeed {
+    /// Seed the stream was created from
+    pub seed: u64,
+    /// Number of 64-bit values drawn from the stream before the roll
+    pub position: u64,
+}
+
+/// A `Pcg64` that remembers its seed and how far through its stream it is
+#[derive(Clone, Debug)]
+pub struct SeededRng {
+    rng: Pcg64,
+    seed: u64,
+    position: u64,
+}
+
+impl SeededRng {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Pcg64::seed_from_u64(seed),
+            seed,
+            position: 0,
+        }
+    }
+
+    /// Pick up a stream at the point a previous roll started from
+    #[must_use]
+    pub fn resume(roll_seed: RollSeed) -> Self {
+        let mut rng = Self::new(roll_seed.seed);
+        rng.rng.advance(roll_seed.position.into());
+        rng.position = roll_seed.position;
+        rng
+    }
+
+    /// A new stream seeded straight from the OS, for a single roll or request. Its seed can
+    /// be handed out, since it says nothing about any other stream.
+    #[must_use]
+    pub fn from_entropy() -> Self {
+        Self::new(OsRng.next_u64())
+    }
+
+    /// Current seed and position in the stream
+    #[must_use]
+    pub const fn roll_seed(&self) -> RollSeed {
+        RollSeed {
+            seed: self.seed,
+            position: self.position,
+        }
+    }
+}
+
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        // Pcg64 uses a whole step for each u32 as well
+        self.position += 1;
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.position += 1;
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        // One step for every 8 bytes, or part thereof
+        self.position += (dest.len() as u64).div_ceil(8);
+        self.rng.fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
 
 #[derive(Default)]
 pub struct RngConnectionManager;
@@ -12,6 +90,8 @@ impl RngConnectionManager {
     }
 }
 
+/// Pooled streams are shared between requests, so their seeds are never handed out.
+/// Rolls that need to be replayed use a `SeededRng` of their own instead.
 impl ManageConnection for RngConnectionManager {
     type Connection = Pcg64;
     type Error = Error;
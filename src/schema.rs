@@ -1,3 +1,35 @@
+table! {
+    advantage_roll_stats (mode, die, roll) {
+        mode -> Text,
+        die -> Int2,
+        roll -> Int2,
+        roll_count -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    roll_history (id) {
+        id -> Int8,
+        instruction -> Text,
+        rolls -> Array<Int4>,
+        total -> Int4,
+        room -> Nullable<Text>,
+        user_name -> Nullable<Text>,
+        private -> Bool,
+        created_at -> Timestamp,
+        user_id -> Nullable<Int4>,
+        seeded -> Bool,
+    }
+}
+
+table! {
+    roll_stat_flushes (batch_id) {
+        batch_id -> Text,
+        flushed_at -> Timestamp,
+    }
+}
+
 table! {
     roll_stats (die, roll) {
         die -> Int2,
@@ -6,3 +38,57 @@ table! {
         updated_at -> Timestamp,
     }
 }
+
+table! {
+    room_gms (room, user_id) {
+        room -> Text,
+        user_id -> Int4,
+    }
+}
+
+table! {
+    rooms (name) {
+        name -> Text,
+        owner_id -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    scoped_roll_stats (scope, scope_id, bucket, bucket_start, die, roll) {
+        scope -> Text,
+        scope_id -> Text,
+        bucket -> Text,
+        bucket_start -> Date,
+        die -> Int2,
+        roll -> Int2,
+        roll_count -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Int4,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+joinable!(roll_history -> users (user_id));
+joinable!(room_gms -> rooms (room));
+joinable!(room_gms -> users (user_id));
+joinable!(rooms -> users (owner_id));
+
+allow_tables_to_appear_in_same_query!(
+    advantage_roll_stats,
+    roll_history,
+    roll_stat_flushes,
+    roll_stats,
+    room_gms,
+    rooms,
+    scoped_roll_stats,
+    users,
+);
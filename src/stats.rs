@@ -0,0 +1,351 @@
+//! Roll stats broken down by who rolled, where, and when
+//!
+//! Counts build up in a Redis hash per scope and time bucket, keyed like the global
+//! `roll_stats` hash, until they are flushed to Postgres.
+use crate::{
+    models::{AdvantageRollStat, RollStat},
+    schema::{advantage_roll_stats, roll_stats},
+    REDIS_KEY_ADVANTAGE_STATS, REDIS_KEY_DISADVANTAGE_STATS, REDIS_KEY_ROLL_STATS,
+};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use diesel::{pg::PgConnection, prelude::*};
+use r2d2_redis::redis::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::BuildHasher,
+    str::FromStr,
+};
+
+pub mod flush;
+
+/// Redis set of scoped stat hashes waiting to be flushed
+pub const REDIS_KEY_SCOPED_STATS: &str = "roll_stats:scoped";
+/// Modes the kept dice are tracked for, as stored in the database, with their Redis hashes
+pub const ADVANTAGE_MODES: [(&str, &str); 2] = [
+    ("advantage", REDIS_KEY_ADVANTAGE_STATS),
+    ("disadvantage", REDIS_KEY_DISADVANTAGE_STATS),
+];
+const KEY_PREFIX: &str = "roll_stats";
+
+/// Who or what a set of stats belongs to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Scope {
+    User(i32),
+    Room(String),
+}
+
+impl Scope {
+    /// Name of the kind of scope, as stored in the database
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::User(_) => "user",
+            Self::Room(_) => "room",
+        }
+    }
+
+    /// User id or room name, as stored in the database
+    #[must_use]
+    pub fn id(&self) -> String {
+        match self {
+            Self::User(id) => id.to_string(),
+            Self::Room(room) => room.clone(),
+        }
+    }
+
+    fn from_parts(kind: &str, id: &str) -> Option<Self> {
+        match kind {
+            "user" => id.parse().ok().map(Self::User),
+            "room" => Some(Self::Room(id.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Span of time stats are grouped by
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    /// Weeks start on a Monday
+    Week,
+}
+
+impl Bucket {
+    pub const ALL: [Self; 2] = [Self::Day, Self::Week];
+
+    /// First day of the bucket a date falls in
+    #[must_use]
+    pub fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Week => date - Duration::days(date.weekday().num_days_from_monday().into()),
+        }
+    }
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Day => write!(f, "day"),
+            Self::Week => write!(f, "week"),
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            _ => Err(()),
+        }
+    }
+}
+
+/// One scope's stats for one time bucket
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatKey {
+    pub scope: Scope,
+    pub bucket: Bucket,
+    pub start: NaiveDate,
+}
+
+impl StatKey {
+    /// Key for every bucket a roll on this date counts towards
+    #[must_use]
+    pub fn all(scope: &Scope, date: NaiveDate) -> Vec<Self> {
+        Bucket::ALL
+            .iter()
+            .map(|&bucket| Self {
+                scope: scope.clone(),
+                bucket,
+                start: bucket.start(date),
+            })
+            .collect()
+    }
+
+    /// Name of the Redis hash the counts are kept in.
+    /// The id goes last, since room names can have colons in them.
+    #[must_use]
+    pub fn redis_key(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            KEY_PREFIX,
+            self.scope.kind(),
+            self.bucket,
+            self.start,
+            self.scope.id()
+        )
+    }
+
+    /// Read a key back from the name of its Redis hash
+    #[must_use]
+    pub fn parse(key: &str) -> Option<Self> {
+        let mut parts = key.splitn(5, ':');
+        if parts.next()? != KEY_PREFIX {
+            return None;
+        }
+        let kind = parts.next()?;
+        let bucket = parts.next()?.parse().ok()?;
+        let start = parts.next()?.parse().ok()?;
+        let scope = Scope::from_parts(kind, parts.next()?)?;
+        Some(Self {
+            scope,
+            bucket,
+            start,
+        })
+    }
+}
+
+/// How often one face of a die has come up
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct FaceStat {
+    pub roll: i16,
+    pub count: i64,
+}
+
+/// Every face rolled so far on one size of die
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DieStats {
+    pub die: i16,
+    pub total: i64,
+    /// Rolls counted that haven't been flushed to Postgres yet
+    pub pending: i64,
+    /// When the saved counts last changed, if any have been saved
+    pub updated_at: Option<NaiveDateTime>,
+    pub faces: Vec<FaceStat>,
+}
+
+/// Add counts still waiting in Redis onto the saved stats, in order of die and roll
+#[must_use]
+pub fn merge_roll_stats<S: BuildHasher>(
+    saved: Vec<RollStat>,
+    pending: &HashMap<(i16, i16), i64, S>,
+) -> Vec<DieStats> {
+    fn die_stats(by_die: &mut BTreeMap<i16, DieStats>, die: i16) -> &mut DieStats {
+        by_die.entry(die).or_insert_with(|| DieStats {
+            die,
+            total: 0,
+            pending: 0,
+            updated_at: None,
+            faces: Vec::new(),
+        })
+    }
+    let mut by_die = BTreeMap::new();
+    let mut faces: BTreeMap<(i16, i16), i64> = BTreeMap::new();
+    for stat in saved {
+        let stats = die_stats(&mut by_die, stat.die);
+        stats.total += stat.roll_count;
+        stats.updated_at = stats.updated_at.max(Some(stat.updated_at));
+        *faces.entry((stat.die, stat.roll)).or_insert(0) += stat.roll_count;
+    }
+    for (&(die, roll), &count) in pending {
+        let stats = die_stats(&mut by_die, die);
+        stats.total += count;
+        stats.pending += count;
+        *faces.entry((die, roll)).or_insert(0) += count;
+    }
+    for ((die, roll), count) in faces {
+        if let Some(stats) = by_die.get_mut(&die) {
+            stats.faces.push(FaceStat { roll, count });
+        }
+    }
+    by_die.into_values().collect()
+}
+
+/// Stats for every die, or just one, including rolls that haven't been flushed yet
+///
+/// # Errors
+///
+/// Will return an error if Redis or Postgres can't be reached, or a hash can't be read
+pub fn current_roll_stats(
+    d_conn: &PgConnection,
+    r_conn: &mut Connection,
+    die: Option<i16>,
+) -> Result<Vec<DieStats>, flush::FlushError> {
+    let mut pending = flush::pending_roll_stats(d_conn, r_conn, REDIS_KEY_ROLL_STATS)?;
+    let mut saved = roll_stats::table.into_boxed();
+    if let Some(die) = die {
+        pending.retain(|&(pending_die, _), _| pending_die == die);
+        saved = saved.filter(roll_stats::die.eq(die));
+    }
+    Ok(merge_roll_stats(saved.load(d_conn)?, &pending))
+}
+
+/// Stats for the die kept from rolls with advantage and disadvantage, by mode, including
+/// rolls that haven't been flushed yet
+///
+/// # Errors
+///
+/// Will return an error if Redis or Postgres can't be reached, or a hash can't be read
+pub fn current_advantage_stats(
+    d_conn: &PgConnection,
+    r_conn: &mut Connection,
+) -> Result<BTreeMap<&'static str, Vec<DieStats>>, flush::FlushError> {
+    let mut modes = BTreeMap::new();
+    for &(mode, key) in &ADVANTAGE_MODES {
+        let pending = flush::pending_roll_stats(d_conn, r_conn, key)?;
+        let saved = advantage_roll_stats::table
+            .filter(advantage_roll_stats::mode.eq(mode))
+            .load::<AdvantageRollStat>(d_conn)?
+            .into_iter()
+            .map(|stat| RollStat {
+                die: stat.die,
+                roll: stat.roll,
+                roll_count: stat.roll_count,
+                updated_at: stat.updated_at,
+            })
+            .collect();
+        modes.insert(mode, merge_roll_stats(saved, &pending));
+    }
+    Ok(modes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_start() {
+        // A Sunday
+        let date = NaiveDate::from_ymd(2021, 3, 14);
+        assert_eq!(Bucket::Day.start(date), date);
+        assert_eq!(Bucket::Week.start(date), NaiveDate::from_ymd(2021, 3, 8));
+        let monday = NaiveDate::from_ymd(2021, 3, 8);
+        assert_eq!(Bucket::Week.start(monday), monday);
+    }
+
+    #[test]
+    fn test_stat_key() {
+        let date = NaiveDate::from_ymd(2021, 3, 14);
+        let keys = StatKey::all(&Scope::Room(String::from("tomb:of:horrors")), date);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[1].redis_key(),
+            "roll_stats:room:week:2021-03-08:tomb:of:horrors"
+        );
+        for key in keys {
+            assert_eq!(StatKey::parse(&key.redis_key()), Some(key));
+        }
+
+        let key = StatKey::all(&Scope::User(7), date).remove(0);
+        assert_eq!(key.redis_key(), "roll_stats:user:day:2021-03-14:7");
+        assert_eq!(StatKey::parse(&key.redis_key()), Some(key));
+
+        assert_eq!(StatKey::parse("roll_stats"), None);
+        assert_eq!(StatKey::parse("roll_stats:user:day:2021-03-14:abc"), None);
+        assert_eq!(StatKey::parse("roll_stats:guild:day:2021-03-14:7"), None);
+    }
+
+    #[test]
+    fn test_merge_roll_stats() {
+        let earlier = NaiveDate::from_ymd(2021, 3, 14).and_hms(12, 0, 0);
+        let later = NaiveDate::from_ymd(2021, 3, 15).and_hms(12, 0, 0);
+        let saved = vec![
+            RollStat {
+                die: 4,
+                roll: 2,
+                roll_count: 3,
+                updated_at: later,
+            },
+            RollStat {
+                die: 4,
+                roll: 1,
+                roll_count: 5,
+                updated_at: earlier,
+            },
+        ];
+        let pending: HashMap<(i16, i16), i64> = vec![((4, 1), 2), ((4, 3), 1), ((7, 7), 4)]
+            .into_iter()
+            .collect();
+        let dice = merge_roll_stats(saved, &pending);
+        assert_eq!(
+            dice,
+            vec![
+                DieStats {
+                    die: 4,
+                    total: 11,
+                    pending: 3,
+                    updated_at: Some(later),
+                    faces: vec![
+                        FaceStat { roll: 1, count: 7 },
+                        FaceStat { roll: 2, count: 3 },
+                        FaceStat { roll: 3, count: 1 },
+                    ],
+                },
+                DieStats {
+                    die: 7,
+                    total: 4,
+                    pending: 4,
+                    updated_at: None,
+                    faces: vec![FaceStat { roll: 7, count: 4 }],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Moving roll stats from Redis into Postgres
+//!
+//! Each hash is renamed to a buffer and given a batch id before anything is saved. Every
+//! buffer is applied in one transaction that also records the batch ids, and buffers are
+//! only deleted once it commits. A flush that dies partway leaves its buffers behind for
+//! the next one to pick up, and a batch that was already saved is skipped rather than
+//! counted twice.
+use super::{StatKey, ADVANTAGE_MODES, REDIS_KEY_SCOPED_STATS};
+use crate::{
+    models::{NewAdvantageRollStat, NewRollStat, NewScopedRollStat},
+    schema, REDIS_KEY_ROLL_STATS,
+};
+use diesel::{self, pg::PgConnection, prelude::*, Connection as _};
+use r2d2_redis::redis::{cmd, Commands, Connection, Script};
+use rand::RngCore;
+use std::{collections::HashMap, error::Error, time::Instant};
+use tide::log::{debug, error, info};
+
+pub type FlushError = Box<dyn Error + Send + Sync>;
+
+// Only one flush runs at a time, across every instance. The lock expires on its own
+// in case whoever holds it dies.
+const FLUSH_LOCK_KEY: &str = "roll_stats:flush_lock";
+const FLUSH_LOCK_SECONDS: usize = 300;
+
+// Field in each buffer holding its batch id. Stats fields are all `die:roll`.
+const BATCH_FIELD: &str = "batch_id";
+// Buffers get a prefix of their own, since room names are up to users and a suffix could
+// turn one room's live stats into another's buffer
+const BUFFER_PREFIX: &str = "roll_stats_buffer";
+
+/// Where a stats hash gets saved to
+enum Target {
+    Global,
+    /// Dice kept with advantage or disadvantage
+    Mode(&'static str),
+    Scoped(StatKey),
+}
+
+/// A stats hash moved aside to be saved
+struct Buffer {
+    key: String,
+    batch_id: String,
+    target: Target,
+    entries: Vec<(i16, i16, i64)>,
+}
+
+fn buffer_key(key: &str) -> String {
+    format!("{}:{}", BUFFER_PREFIX, key)
+}
+
+fn new_batch_id() -> String {
+    let mut id = [0; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// Read a `die:roll` field and its count from a stats hash
+fn parse_entry(field: &str, count: &str) -> Result<(i16, i16, i64), FlushError> {
+    let mut key = field.split(':');
+    Ok((
+        key.next().ok_or("Missing die")?.parse()?,
+        key.next().ok_or("Missing roll")?.parse()?,
+        count.parse()?,
+    ))
+}
+
+/// Move a stats hash into its buffer, or pick up the buffer a failed run left behind
+fn take_buffer(
+    r_conn: &mut Connection,
+    key: &str,
+    target: Target,
+) -> Result<Option<Buffer>, FlushError> {
+    let buffer = buffer_key(key);
+    let leftover: bool = r_conn.exists(&buffer)?;
+    if leftover {
+        info!("Resuming leftover {}", buffer);
+    } else {
+        let exists: bool = r_conn.exists(key)?;
+        if !exists {
+            return Ok(None);
+        }
+        r_conn.rename_nx::<_, bool>(key, buffer.as_str())?;
+    }
+    // Only set once, so a resumed buffer keeps the id it may already have been saved with
+    r_conn.hset_nx::<_, _, _, ()>(&buffer, BATCH_FIELD, new_batch_id())?;
+
+    let mut fields: HashMap<String, String> = r_conn.hgetall(&buffer)?;
+    let batch_id = fields.remove(BATCH_FIELD).ok_or("Missing batch id")?;
+    let mut entries = Vec::new();
+    for (field, count) in &fields {
+        entries.push(parse_entry(field, count)?);
+    }
+    Ok(Some(Buffer {
+        key: buffer,
+        batch_id,
+        target,
+        entries,
+    }))
+}
+
+/// Add a buffer's counts to the stats, unless its batch has already been saved
+fn apply_buffer(d_conn: &PgConnection, buffer: &Buffer) -> QueryResult<bool> {
+    use schema::roll_stat_flushes::dsl::{batch_id, roll_stat_flushes};
+
+    let new_batch = diesel::insert_into(roll_stat_flushes)
+        .values(batch_id.eq(&buffer.batch_id))
+        .on_conflict_do_nothing()
+        .execute(d_conn)?;
+    if new_batch == 0 {
+        return Ok(false);
+    }
+
+    for &(new_die, new_roll, count) in &buffer.entries {
+        match &buffer.target {
+            Target::Global => {
+                use schema::roll_stats::dsl::{die, roll, roll_count, roll_stats};
+                // Dice of any size can be rolled, so faces are added as they show up
+                diesel::insert_into(roll_stats)
+                    .values(&NewRollStat {
+                        die: new_die,
+                        roll: new_roll,
+                        roll_count: count,
+                    })
+                    .on_conflict((die, roll))
+                    .do_update()
+                    .set(roll_count.eq(roll_count + count))
+                    .execute(d_conn)?;
+            }
+            Target::Mode(new_mode) => {
+                use schema::advantage_roll_stats::dsl::{
+                    advantage_roll_stats, die, mode, roll, roll_count,
+                };
+                diesel::insert_into(advantage_roll_stats)
+                    .values(&NewAdvantageRollStat {
+                        mode: new_mode,
+                        die: new_die,
+                        roll: new_roll,
+                        roll_count: count,
+                    })
+                    .on_conflict((mode, die, roll))
+                    .do_update()
+                    .set(roll_count.eq(roll_count + count))
+                    .execute(d_conn)?;
+            }
+            Target::Scoped(stat_key) => {
+                use schema::scoped_roll_stats::dsl::{
+                    bucket, bucket_start, die, roll, roll_count, scope, scope_id, scoped_roll_stats,
+                };
+                diesel::insert_into(scoped_roll_stats)
+                    .values(&NewScopedRollStat {
+                        scope: stat_key.scope.kind(),
+                        scope_id: &stat_key.scope.id(),
+                        bucket: &stat_key.bucket.to_string(),
+                        bucket_start: stat_key.start,
+                        die: new_die,
+                        roll: new_roll,
+                        roll_count: count,
+                    })
+                    .on_conflict((scope, scope_id, bucket, bucket_start, die, roll))
+                    .do_update()
+                    .set(roll_count.eq(roll_count + count))
+                    .execute(d_conn)?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// What a flush did, for logging
+#[derive(Debug, Default)]
+pub struct FlushReport {
+    /// Hashes that were saved
+    pub batches: usize,
+    /// Hashes that had already been saved by an earlier flush
+    pub skipped: usize,
+    /// Faces whose counts were saved
+    pub stats: usize,
+    pub duration_ms: u128,
+}
+
+/// Save every buffered count to Postgres
+///
+/// # Errors
+///
+/// Will return an error if Redis or Postgres can't be reached, or a hash can't be read.
+/// Nothing is saved in that case, and the next flush picks up where this one left off.
+pub fn flush_roll_stats(
+    d_conn: &PgConnection,
+    r_conn: &mut Connection,
+) -> Result<FlushReport, FlushError> {
+    let started = Instant::now();
+    let mut buffers = Vec::new();
+    buffers.extend(take_buffer(r_conn, REDIS_KEY_ROLL_STATS, Target::Global)?);
+    for &(mode, key) in &ADVANTAGE_MODES {
+        buffers.extend(take_buffer(r_conn, key, Target::Mode(mode))?);
+    }
+
+    // The list of scoped hashes is buffered the same way, so none are missed on a resume
+    let scoped_buffer = buffer_key(REDIS_KEY_SCOPED_STATS);
+    let leftover: bool = r_conn.exists(&scoped_buffer)?;
+    let scoped_exists: bool = r_conn.exists(REDIS_KEY_SCOPED_STATS)?;
+    if !leftover && scoped_exists {
+        r_conn.rename_nx::<_, bool>(REDIS_KEY_SCOPED_STATS, scoped_buffer.as_str())?;
+    }
+    let keys: Vec<String> = r_conn.smembers(&scoped_buffer)?;
+    for key in keys {
+        if let Some(stat_key) = StatKey::parse(&key) {
+            buffers.extend(take_buffer(r_conn, &key, Target::Scoped(stat_key))?);
+        } else {
+            error!("Unknown stats key: {}", key);
+        }
+    }
+
+    let mut report = FlushReport::default();
+    d_conn.transaction::<_, diesel::result::Error, _>(|| {
+        for buffer in &buffers {
+            if apply_buffer(d_conn, buffer)? {
+                debug!("Saved {} stats from {}", buffer.entries.len(), buffer.key);
+                report.batches += 1;
+                report.stats += buffer.entries.len();
+            } else {
+                info!("Batch {} was already saved", buffer.batch_id);
+                report.skipped += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    // Everything is saved, so the buffers can go
+    for buffer in &buffers {
+        r_conn.del::<_, ()>(&buffer.key)?;
+    }
+    r_conn.del::<_, ()>(&scoped_buffer)?;
+    report.duration_ms = started.elapsed().as_millis();
+    Ok(report)
+}
+
+/// Counts in a global stats hash that aren't in Postgres yet, by die and roll
+///
+/// This includes a buffer left mid-flush, unless its batch has already been saved. A flush
+/// that commits while this runs can be missed or counted twice, until the next call.
+///
+/// # Errors
+///
+/// Will return an error if Redis or Postgres can't be reached, or a hash can't be read
+pub fn pending_roll_stats(
+    d_conn: &PgConnection,
+    r_conn: &mut Connection,
+    key: &str,
+) -> Result<HashMap<(i16, i16), i64>, FlushError> {
+    use schema::roll_stat_flushes::dsl::{batch_id, roll_stat_flushes};
+
+    let fields: HashMap<String, String> = r_conn.hgetall(key)?;
+    let mut buffered: HashMap<String, String> = r_conn.hgetall(buffer_key(key))?;
+    let saved = match buffered.remove(BATCH_FIELD) {
+        Some(id) => diesel::select(diesel::dsl::exists(
+            roll_stat_flushes.filter(batch_id.eq(id)),
+        ))
+        .get_result(d_conn)?,
+        // Not given a batch id yet, so it can't have been saved
+        None => false,
+    };
+
+    if saved {
+        buffered.clear();
+    }
+
+    let mut pending = HashMap::new();
+    for (field, count) in fields.iter().chain(&buffered) {
+        let (die, roll, count) = parse_entry(field, count)?;
+        *pending.entry((die, roll)).or_insert(0) += count;
+    }
+    Ok(pending)
+}
+
+/// Flush, as long as nobody else is already. `None` if someone else is.
+///
+/// # Errors
+///
+/// Will return an error if the lock can't be taken, or the flush fails
+pub fn flush_with_lock(
+    d_conn: &PgConnection,
+    r_conn: &mut Connection,
+) -> Result<Option<FlushReport>, FlushError> {
+    let token = new_batch_id();
+    let locked: Option<String> = cmd("SET")
+        .arg(FLUSH_LOCK_KEY)
+        .arg(&token)
+        .arg("NX")
+        .arg("EX")
+        .arg(FLUSH_LOCK_SECONDS)
+        .query(r_conn)?;
+    if locked.is_none() {
+        return Ok(None);
+    }
+    let report = flush_roll_stats(d_conn, r_conn);
+    // Only let go of the lock if it is still ours
+    Script::new(
+        r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) end return 0"#,
+    )
+    .key(FLUSH_LOCK_KEY)
+    .arg(&token)
+    .invoke::<()>(r_conn)?;
+    report.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Scope;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_buffer_key() {
+        let date = NaiveDate::from_ymd(2021, 3, 14);
+        let room = StatKey::all(&Scope::Room(String::from("x")), date).remove(0);
+        let other = StatKey::all(&Scope::Room(String::from("x_buffer")), date).remove(0);
+        assert_ne!(buffer_key(&room.redis_key()), other.redis_key());
+        assert_eq!(StatKey::parse(&buffer_key(&room.redis_key())), None);
+    }
+}